@@ -0,0 +1,16 @@
+use std::process::Command;
+
+fn main() {
+    // Embed the commit hash for `--version` when building from a git checkout.
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=THORIUM_GIT_HASH={}", hash.trim());
+        }
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
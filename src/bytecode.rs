@@ -0,0 +1,269 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    chunk::Chunk,
+    value::{Function, Value},
+};
+
+const MAGIC: &[u8] = b"THOR";
+pub const BYTECODE_VERSION: u8 = 2;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(function: &Function) -> Result<Vec<u8>, String> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(BYTECODE_VERSION);
+    write_function(&mut bytes, function)?;
+    Ok(bytes)
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Function, String> {
+    let mut reader = Reader { bytes, position: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a thorium bytecode file.".to_string());
+    }
+
+    let version = reader.byte()?;
+    if version != BYTECODE_VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {version}, expected {BYTECODE_VERSION}."
+        ));
+    }
+
+    let function = reader.function()?;
+    if reader.position != bytes.len() {
+        return Err("Corrupt bytecode: trailing data after chunk.".to_string());
+    }
+
+    Ok(function)
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &Function) -> Result<(), String> {
+    write_str(bytes, &function.name);
+    write_u32(bytes, function.arity as u32);
+    write_chunk(bytes, &function.chunk)
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) -> Result<(), String> {
+    write_u32(bytes, chunk.code.len() as u32);
+    bytes.extend_from_slice(&chunk.code);
+    for line in &chunk.lines {
+        write_u32(bytes, *line as u32);
+    }
+
+    write_u32(bytes, chunk.constants.values.len() as u32);
+    for constant in &chunk.constants.values {
+        write_value(bytes, constant)?;
+    }
+    Ok(())
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Nil => bytes.push(0),
+        Value::Boolean(b) => bytes.extend([1, *b as u8]),
+        Value::Number(n) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        Value::DynamicString(s) => {
+            bytes.push(3);
+            write_str(bytes, s);
+        }
+        Value::Bytes(b) => {
+            bytes.push(4);
+            write_u32(bytes, b.len() as u32);
+            bytes.extend_from_slice(b);
+        }
+        Value::Array(values) => {
+            bytes.push(5);
+            let values = values.borrow();
+            write_u32(bytes, values.len() as u32);
+            for value in values.iter() {
+                write_value(bytes, value)?;
+            }
+        }
+        Value::Function(function) => {
+            bytes.push(6);
+            write_function(bytes, function)?;
+        }
+        Value::Int(n) => {
+            bytes.push(7);
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        Value::Map(entries) => {
+            bytes.push(8);
+            let entries = entries.borrow();
+            write_u32(bytes, entries.len() as u32);
+            for (key, value) in entries.iter() {
+                write_str(bytes, key);
+                write_value(bytes, value)?;
+            }
+        }
+        Value::NativeFunction(native) => {
+            return Err(format!("Cannot serialize native function {}.", native.name))
+        }
+        Value::Class(class) => return Err(format!("Cannot serialize class {}.", class.name)),
+        Value::Instance(instance) => {
+            return Err(format!(
+                "Cannot serialize {} instance.",
+                instance.class.name
+            ))
+        }
+        Value::BoundMethod(bound) => {
+            return Err(format!("Cannot serialize method {}.", bound.method.name))
+        }
+    }
+    Ok(())
+}
+
+fn write_u32(bytes: &mut Vec<u8>, n: u32) {
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len() as u32);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position + length;
+        let slice = self
+            .bytes
+            .get(self.position..end)
+            .ok_or("Corrupt bytecode: unexpected end of data.")?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| "Corrupt bytecode: invalid UTF-8 in string.".to_string())
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let mut function = Function::init(&self.string()?);
+        function.arity = self.u32()? as usize;
+        function.chunk = self.chunk()?;
+        Ok(function)
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let mut chunk = Chunk::init();
+        let length = self.u32()? as usize;
+        chunk.code = self.take(length)?.to_vec();
+        for _ in 0..length {
+            chunk.lines.push(self.u32()? as usize);
+        }
+
+        let constants = self.u32()?;
+        for _ in 0..constants {
+            let value = self.value()?;
+            chunk.add_constant(value);
+        }
+        Ok(chunk)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let value = match self.byte()? {
+            0 => Value::Nil,
+            1 => Value::Boolean(self.byte()? != 0),
+            2 => {
+                let bytes = self.take(4)?;
+                Value::Number(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            3 => Value::DynamicString(self.string()?),
+            4 => {
+                let length = self.u32()? as usize;
+                Value::Bytes(self.take(length)?.to_vec())
+            }
+            5 => {
+                let length = self.u32()?;
+                let mut values = Vec::new();
+                for _ in 0..length {
+                    values.push(self.value()?);
+                }
+                Value::array(values)
+            }
+            6 => Value::Function(Rc::new(self.function()?)),
+            7 => {
+                let bytes = self.take(8)?;
+                Value::Int(i64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            8 => {
+                let length = self.u32()?;
+                let mut entries = HashMap::new();
+                for _ in 0..length {
+                    let key = self.string()?;
+                    entries.insert(key, self.value()?);
+                }
+                Value::map(entries)
+            }
+            tag => return Err(format!("Corrupt bytecode: unknown constant tag {tag}.")),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::CompileOptions,
+        testing::{compile, run_function},
+    };
+
+    const SCRIPT: &str = r#"
+        fun greet(name) { return "hi " + name; }
+        print greet("there");
+        print [1, 2.5, nil, true];
+        print b"\x01";
+    "#;
+
+    #[test]
+    fn serialized_scripts_load_and_run() {
+        let function = compile(SCRIPT, &CompileOptions::default());
+        let bytes = serialize(&function).unwrap();
+        assert_eq!(bytes[MAGIC.len()], BYTECODE_VERSION);
+
+        let loaded = deserialize(&bytes).unwrap();
+        assert_eq!(loaded.chunk.code, function.chunk.code);
+        assert_eq!(loaded.chunk.lines, function.chunk.lines);
+        let (result, output) = run_function(loaded);
+        assert!(result.is_ok());
+        assert_eq!(output, "hi there\n[1, 2.5, nil, true]\nb\"\\x01\"\n");
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let function = compile(SCRIPT, &CompileOptions::default());
+        let mut bytes = serialize(&function).unwrap();
+        bytes[MAGIC.len()] = BYTECODE_VERSION + 1;
+        assert_eq!(
+            deserialize(&bytes).unwrap_err(),
+            format!(
+                "Unsupported bytecode version {}, expected {BYTECODE_VERSION}.",
+                BYTECODE_VERSION + 1
+            )
+        );
+    }
+}
@@ -1,36 +1,119 @@
 use crate::value::{Value, ValueArray};
 use colored::*;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::convert::TryFrom;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io::{self, Write},
+};
 
+// Discriminants are pinned because they are written to serialized bytecode.
+// Bump `bytecode::BYTECODE_VERSION` when changing them. The same goes for
+// adding, removing or reordering `natives::NATIVES`: natives take the first
+// global slots, so the slot operands of `GetGlobalFast`/`SetGlobalFast` in a
+// saved file only mean the same globals under the same table.
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
-    Return,
-    Constant,
-    Negate,
-    Add,
-    Subtract,
-    Divide,
-    Multiply,
-    True,
-    False,
-    Nil,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    DefineGlobal,
-    GetGlobal,
-    SetGlobal,
+    Return = 0,
+    Constant = 1,
+    Negate = 2,
+    Add = 3,
+    Subtract = 4,
+    Divide = 5,
+    Multiply = 6,
+    True = 7,
+    False = 8,
+    Nil = 9,
+    Not = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Print = 14,
+    Pop = 15,
+    DefineGlobal = 16,
+    GetGlobal = 17,
+    SetGlobal = 18,
+    GetLocal = 19,
+    SetLocal = 20,
+    Call = 21,
+    PopN = 22,
+    BuildArray = 23,
+    Jump = 24,
+    JumpIfFalse = 25,
+    Loop = 26,
+    GetIndex = 27,
+    Length = 28,
+    AssertNumber = 29,
+    Swap = 30,
+    BuildMap = 31,
+    Constant0 = 32,
+    Constant1 = 33,
+    ConstantMinus1 = 34,
+    GetGlobalFast = 35,
+    SetGlobalFast = 36,
+    DefineGlobalConst = 37,
+    Dup = 38,
+    CheckNumber = 39,
+    Class = 40,
+    GetProperty = 41,
+    SetProperty = 42,
+    Method = 43,
+    SetIndex = 44,
+    Dup2 = 45,
 }
 
+impl OpCode {
+    pub fn operand_len(&self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalConst
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetGlobalFast
+            | OpCode::SetGlobalFast
+            | OpCode::Call
+            | OpCode::PopN
+            | OpCode::BuildArray
+            | OpCode::BuildMap => 1,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => 2,
+            _ => 0,
+        }
+    }
+
+    // Whether the one-byte operand is an index into the constant table.
+    pub fn has_constant_operand(&self) -> bool {
+        matches!(
+            self,
+            OpCode::Constant
+                | OpCode::DefineGlobal
+                | OpCode::DefineGlobalConst
+                | OpCode::Class
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Method
+                | OpCode::GetGlobal
+                | OpCode::SetGlobal
+        )
+    }
+}
+
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ValueArray,
     pub lines: Vec<usize>,
+    // Variable names for slot-based instructions, by offset, so errors and the
+    // disassembler can name them. Only debug builds record these and they are
+    // never serialized.
+    pub debug_names: Vec<(usize, String)>,
 }
 
 impl Chunk {
@@ -39,6 +122,7 @@ impl Chunk {
             code: Vec::new(),
             constants: ValueArray::init(),
             lines: Vec::new(),
+            debug_names: Vec::new(),
         }
     }
 
@@ -47,28 +131,227 @@ impl Chunk {
         self.lines.push(line);
     }
 
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.debug_names.retain(|(offset, _)| *offset < len);
+    }
+
+    pub fn add_debug_name(&mut self, offset: usize, name: &str) {
+        self.debug_names.push((offset, name.to_string()));
+    }
+
+    pub fn debug_name(&self, offset: usize) -> Option<&str> {
+        self.debug_names
+            .binary_search_by_key(&offset, |(o, _)| *o)
+            .ok()
+            .map(|i| self.debug_names[i].1.as_str())
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.write(value);
         self.constants.values.len() - 1
     }
 
+    // Appends another chunk's code, lines and constants. Constant operands in
+    // the appended code are shifted past this chunk's constants; jumps are
+    // relative and need no fixing. Fails without changing anything when a
+    // shifted index no longer fits in one byte.
+    pub fn append(&mut self, other: &Chunk) -> Result<(), String> {
+        let base = self.constants.values.len();
+        let mut code = other.code.clone();
+        for (offset, op, operands) in other.instructions() {
+            if !op.has_constant_operand() || operands.is_empty() {
+                continue;
+            }
+
+            let index = base + operands[0] as usize;
+            code[offset + 1] = u8::try_from(index)
+                .map_err(|_| format!("Too many constants to append chunk ({index})."))?;
+        }
+
+        let start = self.code.len();
+        self.code.extend(code);
+        self.lines.extend_from_slice(&other.lines);
+        for (offset, name) in &other.debug_names {
+            self.add_debug_name(start + offset, name);
+        }
+        for value in &other.constants.values {
+            self.constants.write(value.clone());
+        }
+        Ok(())
+    }
+
+    // Merges runs of consecutive `Pop`/`PopN` instructions into a single `PopN`,
+    // keeping the line of the first pop in each run. Runs are split at jump
+    // targets and jump offsets are relocated to the shortened code.
+    pub fn coalesce_pops(&mut self) {
+        let targets: HashSet<usize> = self
+            .jump_offsets()
+            .filter_map(|o| self.jump_target(o))
+            .collect();
+        let mut relocations = HashMap::new();
+        let mut jumps = Vec::new();
+        let mut code = Vec::with_capacity(self.code.len());
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut offset = 0;
+
+        while offset < self.code.len() {
+            let line = self.lines[offset];
+            relocations.insert(offset, code.len());
+
+            if self.pops_at(offset).is_none() {
+                if self.jump_target(offset).is_some() {
+                    jumps.push((offset, code.len()));
+                }
+
+                let end = offset + self.instruction_len(offset);
+                code.extend_from_slice(&self.code[offset..end]);
+                lines.extend_from_slice(&self.lines[offset..end]);
+                offset = end;
+                continue;
+            }
+
+            let mut count = 0;
+            while let Some(pops) = self.pops_at(offset) {
+                if count > 0 && targets.contains(&offset) {
+                    break;
+                }
+
+                count += pops;
+                offset += self.instruction_len(offset);
+            }
+
+            while count > 0 {
+                if count == 1 {
+                    code.push(OpCode::Pop.into());
+                    lines.push(line);
+                    break;
+                }
+
+                let n = count.min(u8::MAX as usize);
+                code.extend([OpCode::PopN.into(), n as u8]);
+                lines.extend([line, line]);
+                count -= n;
+            }
+        }
+        relocations.insert(self.code.len(), code.len());
+
+        for (old_offset, new_offset) in jumps {
+            let target = relocations[&self.jump_target(old_offset).unwrap()];
+            let jump = if self.code[old_offset] == u8::from(OpCode::Loop) {
+                new_offset + 3 - target
+            } else {
+                target - (new_offset + 3)
+            };
+            code[new_offset + 1] = ((jump >> 8) & 0xff) as u8;
+            code[new_offset + 2] = (jump & 0xff) as u8;
+        }
+
+        for (offset, _) in self.debug_names.iter_mut() {
+            *offset = relocations[offset];
+        }
+
+        self.code = code;
+        self.lines = lines;
+    }
+
+    // Decodes each instruction into its offset, opcode and operand bytes.
+    // Bytes that aren't a known opcode are skipped; operands of an instruction
+    // truncated by the end of the chunk are cut short.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, OpCode, &[u8])> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            while offset < self.code.len() {
+                let current = offset;
+                offset += self.instruction_len(current);
+                if let Ok(op) = OpCode::try_from(self.code[current]) {
+                    return Some((current, op, &self.code[current + 1..offset]));
+                }
+            }
+            None
+        })
+    }
+
+    // Returns the source line of the byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        self.lines.get(offset).copied()
+    }
+
+    pub fn constant(&self, index: usize) -> Option<&Value> {
+        self.constants.get(index)
+    }
+
+    fn jump_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.instructions()
+            .map(|(offset, _, _)| offset)
+            .filter(|offset| self.jump_target(*offset).is_some())
+    }
+
+    // Returns the offset a jump instruction at `offset` lands on.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let op = OpCode::try_from(*self.code.get(offset)?).ok()?;
+        let jump =
+            ((*self.code.get(offset + 1)? as usize) << 8) | *self.code.get(offset + 2)? as usize;
+        match op {
+            OpCode::Jump | OpCode::JumpIfFalse => Some(offset + 3 + jump),
+            OpCode::Loop => (offset + 3).checked_sub(jump),
+            _ => None,
+        }
+    }
+
+    fn instruction_len(&self, offset: usize) -> usize {
+        let len = match OpCode::try_from(self.code[offset]) {
+            Ok(op) => 1 + op.operand_len(),
+            Err(_) => 1,
+        };
+        len.min(self.code.len() - offset)
+    }
+
+    fn pops_at(&self, offset: usize) -> Option<usize> {
+        match OpCode::try_from(*self.code.get(offset)?) {
+            Ok(OpCode::Pop) => Some(1),
+            Ok(OpCode::PopN) => self.code.get(offset + 1).map(|n| *n as usize),
+            _ => None,
+        }
+    }
+
+    // Printed through `print!` like the rest of the debug output, so it is
+    // captured along with it.
     pub fn disassemble(&self, name: &str) {
-        println!(
+        let mut text = Vec::new();
+        self.write_disassembly(name, &mut text)
+            .expect("Failed to write disassembly");
+        print!("{}", String::from_utf8_lossy(&text));
+    }
+
+    // Like `disassemble`, but to any writer, such as the REPL's transcript.
+    pub fn write_disassembly(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
             "{}",
             format!("\nDisassemble {name}\n")
                 .magenta()
                 .bold()
                 .underline()
-        );
+        )?;
 
         let mut offset = 0;
+        // The line of the instruction before, shown as `|` when repeated.
+        let mut previous_line = None;
 
         while offset < self.code.len() {
             let op = self.code[offset];
             let op_code = OpCode::try_from(op);
+            let start = offset;
 
+            // A stray byte may really be the operand of an earlier instruction,
+            // so show it raw to make the point where decoding went wrong easy to find.
             if let Err(value) = op_code {
-                display(self, None, offset, &format!("{}", value.number));
+                let data = format!("{:#04x} ({}) <- not an opcode", value.number, value.number);
+                let data = data.red().to_string();
+                write_instruction(out, self, None, offset, previous_line, &data)?;
+                previous_line = self.line_at(offset);
                 offset += 1;
                 continue;
             }
@@ -89,51 +372,317 @@ impl Chunk {
                     | OpCode::Greater
                     | OpCode::Less
                     | OpCode::Print
-                    | OpCode::Pop => offset = display_simple_instruction(&code, offset, self),
+                    | OpCode::Pop
+                    | OpCode::GetIndex
+                    | OpCode::Length
+                    | OpCode::AssertNumber
+                    | OpCode::Swap
+                    | OpCode::Dup
+                    | OpCode::Dup2
+                    | OpCode::SetIndex
+                    | OpCode::CheckNumber
+                    | OpCode::Constant0
+                    | OpCode::Constant1
+                    | OpCode::ConstantMinus1 => {
+                        offset =
+                            display_simple_instruction(out, &code, offset, previous_line, self)?
+                    }
 
                     OpCode::Constant
                     | OpCode::DefineGlobal
+                    | OpCode::DefineGlobalConst
+                    | OpCode::Class
+                    | OpCode::GetProperty
+                    | OpCode::SetProperty
+                    | OpCode::Method
                     | OpCode::GetGlobal
                     | OpCode::SetGlobal => {
-                        offset = display_constant_instruction(&code, offset, self)
+                        offset =
+                            display_constant_instruction(out, &code, offset, previous_line, self)?
+                    }
+
+                    OpCode::GetLocal
+                    | OpCode::SetLocal
+                    | OpCode::GetGlobalFast
+                    | OpCode::SetGlobalFast => {
+                        offset = display_byte_instruction(
+                            out,
+                            &code,
+                            offset,
+                            previous_line,
+                            self,
+                            "Slot",
+                        )?
+                    }
+
+                    OpCode::Call => {
+                        offset = display_byte_instruction(
+                            out,
+                            &code,
+                            offset,
+                            previous_line,
+                            self,
+                            "Args",
+                        )?
+                    }
+
+                    OpCode::PopN | OpCode::BuildArray | OpCode::BuildMap => {
+                        offset = display_byte_instruction(
+                            out,
+                            &code,
+                            offset,
+                            previous_line,
+                            self,
+                            "Count",
+                        )?
+                    }
+
+                    OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                        offset = display_jump_instruction(out, &code, offset, previous_line, self)?
                     }
                 }
             }
+            previous_line = self.line_at(start);
         }
+        Ok(())
     }
 }
 
+// Shows a single instruction, such as the one the VM is about to run, with
+// its line always written out.
 pub fn display(chunk: &Chunk, op: Option<&OpCode>, offset: usize, data: &str) {
-    println!(
-        "{:0>4}\t{}\t{} {}",
-        format!("{:?}", offset).green(),
-        if (offset > 0) && (chunk.lines[offset] == chunk.lines[offset - 1]) {
-            "|".to_string()
+    let mut text = Vec::new();
+    write_instruction(&mut text, chunk, op, offset, None, data)
+        .expect("Failed to write instruction");
+    print!("{}", String::from_utf8_lossy(&text));
+}
+
+fn write_instruction(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    op: Option<&OpCode>,
+    offset: usize,
+    previous_line: Option<usize>,
+    data: &str,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}",
+        format_instruction(chunk, op, offset, previous_line, data, true)
+    )
+}
+
+// The offset, line (`|` when it is `previous_line`), mnemonic and operand text
+// of one instruction, colored for a terminal or plain.
+pub fn format_instruction(
+    chunk: &Chunk,
+    op: Option<&OpCode>,
+    offset: usize,
+    previous_line: Option<usize>,
+    data: &str,
+    color: bool,
+) -> String {
+    let paint = |text: ColoredString| {
+        if color {
+            text.to_string()
         } else {
-            format!("{}", chunk.lines[offset])
+            text.clear().to_string()
+        }
+    };
+    let line = chunk.line_at(offset);
+    format!(
+        "{:0>4}\t{}\t{} {}",
+        paint(format!("{:?}", offset).green()),
+        match line {
+            Some(_) if line == previous_line => "|".to_string(),
+            Some(line) => format!("{}", line),
+            None => "?".to_string(),
         },
         if let Some(mnemonic) = op {
-            format!("{:?}", mnemonic).blue().bold()
+            paint(format!("{:?}", mnemonic).blue().bold())
         } else {
-            "Unknown OP".to_string().red().bold()
+            paint("Unknown OP".to_string().red().bold())
         },
         data
-    );
+    )
 }
 
-fn display_simple_instruction(op: &OpCode, offset: usize, chunk: &Chunk) -> usize {
-    display(chunk, Some(op), offset, "");
-    offset + 1
+fn display_simple_instruction(
+    out: &mut dyn Write,
+    op: &OpCode,
+    offset: usize,
+    previous_line: Option<usize>,
+    chunk: &Chunk,
+) -> io::Result<usize> {
+    write_instruction(out, chunk, Some(op), offset, previous_line, "")?;
+    Ok(offset + 1)
 }
 
-fn display_constant_instruction(op: &OpCode, offset: usize, chunk: &Chunk) -> usize {
-    let constant_index = chunk.code[offset + 1];
-    let constant_value = chunk.constants.values[constant_index as usize].clone();
-    display(
-        chunk,
-        Some(op),
-        offset,
-        &format!("Index={constant_index} Value={constant_value}"),
-    );
-    offset + 2
+fn display_constant_instruction(
+    out: &mut dyn Write,
+    op: &OpCode,
+    offset: usize,
+    previous_line: Option<usize>,
+    chunk: &Chunk,
+) -> io::Result<usize> {
+    // Bytecode read from a file may be cut short or point past its constants.
+    let data = match chunk.code.get(offset + 1) {
+        Some(index) => match chunk.constants.values.get(*index as usize) {
+            Some(value) => format!("Index={index} Value={value}"),
+            None => format!("Index={index} <bad constant>"),
+        },
+        None => "<truncated>".to_string(),
+    };
+    write_instruction(out, chunk, Some(op), offset, previous_line, &data)?;
+    Ok(offset + 2)
+}
+
+fn display_byte_instruction(
+    out: &mut dyn Write,
+    op: &OpCode,
+    offset: usize,
+    previous_line: Option<usize>,
+    chunk: &Chunk,
+    label: &str,
+) -> io::Result<usize> {
+    let data = match (chunk.code.get(offset + 1), chunk.debug_name(offset)) {
+        (Some(operand), Some(name)) => format!("{label}={operand} Name={name}"),
+        (Some(operand), None) => format!("{label}={operand}"),
+        (None, _) => "<truncated>".to_string(),
+    };
+    write_instruction(out, chunk, Some(op), offset, previous_line, &data)?;
+    Ok(offset + 2)
+}
+
+fn display_jump_instruction(
+    out: &mut dyn Write,
+    op: &OpCode,
+    offset: usize,
+    previous_line: Option<usize>,
+    chunk: &Chunk,
+) -> io::Result<usize> {
+    let data = match chunk.jump_target(offset) {
+        Some(target) => format!("{offset} -> {target}"),
+        None if chunk.code.len() < offset + 3 => "<truncated>".to_string(),
+        None => format!("{offset} -> <before start>"),
+    };
+    write_instruction(out, chunk, Some(op), offset, previous_line, &data)?;
+    Ok(offset + 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(code: Vec<(OpCode, usize)>) -> Chunk {
+        let mut chunk = Chunk::init();
+        for (op, line) in code {
+            chunk.write(op.into(), line);
+        }
+        chunk
+    }
+
+    #[test]
+    fn consecutive_pops_become_one_pop_n() {
+        let mut chunk = chunk(vec![
+            (OpCode::Nil, 1),
+            (OpCode::Nil, 1),
+            (OpCode::Nil, 1),
+            (OpCode::Pop, 2),
+            (OpCode::Pop, 3),
+            (OpCode::Pop, 3),
+            (OpCode::Return, 4),
+        ]);
+        chunk.coalesce_pops();
+
+        let pop_n = u8::from(OpCode::PopN);
+        let ret = u8::from(OpCode::Return);
+        assert_eq!(chunk.code[3..], [pop_n, 3, ret]);
+        // The merged pop keeps the line of the first one.
+        assert_eq!(chunk.line_at(3), Some(2));
+        assert_eq!(chunk.line_at(5), Some(4));
+    }
+
+    #[test]
+    fn repeated_lines_show_as_a_bar() {
+        let mut chunk = Chunk::init();
+        let index = chunk.add_constant(Value::Number(1.0)) as u8;
+        for (byte, line) in [
+            (OpCode::Constant.into(), 1),
+            (index, 1),
+            (OpCode::Print.into(), 1),
+            (OpCode::Constant.into(), 3),
+            (index, 3),
+            (OpCode::Print.into(), 3),
+            (OpCode::Return.into(), 4),
+        ] {
+            chunk.write(byte, line);
+        }
+
+        let mut text = Vec::new();
+        chunk.write_disassembly("test", &mut text).unwrap();
+        let lines: Vec<String> = String::from_utf8(text)
+            .unwrap()
+            .lines()
+            .filter_map(|row| row.split('\t').nth(1).map(str::to_string))
+            .collect();
+        assert_eq!(lines, ["1", "|", "3", "|", "4"]);
+    }
+
+    #[test]
+    fn appended_chunks_run_like_the_two_separately() {
+        use crate::{compiler::CompileOptions, testing};
+
+        let options = CompileOptions::default();
+        let first = "print 1 + 2; print \"one\";";
+        let second = "print \"two\"; print 3.5; print 1 + 2;";
+        let mut merged = testing::compile(first, &options);
+        let len = merged.chunk.code.len();
+        merged.chunk.truncate(len - 2);
+        let other = testing::compile(second, &options);
+        merged.chunk.append(&other.chunk).unwrap();
+
+        let (result, printed) = testing::run_function(merged);
+        result.unwrap();
+        assert_eq!(printed, testing::output(first) + &testing::output(second));
+        assert_eq!(printed, "3\none\ntwo\n3.5\n3\n");
+    }
+
+    #[test]
+    fn stray_bytes_show_their_offset_and_value() {
+        let mut chunk = chunk(vec![(OpCode::Nil, 1)]);
+        chunk.write(0xfa, 1);
+        chunk.write(OpCode::Return.into(), 2);
+
+        let mut text = Vec::new();
+        chunk.write_disassembly("test", &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let stray = text.lines().find(|row| row.contains("0xfa")).unwrap();
+        assert!(stray.contains("0001"), "{stray}");
+        assert!(stray.contains("Unknown OP"), "{stray}");
+        assert!(stray.contains("0xfa (250) <- not an opcode"), "{stray}");
+        // Decoding carries on with the next byte.
+        assert!(text.lines().last().unwrap().contains("Return"));
+    }
+
+    #[test]
+    fn bad_operands_are_marked_instead_of_panicking() {
+        let disassembly = |code: &[u8]| {
+            let mut chunk = Chunk::init();
+            for byte in code {
+                chunk.write(*byte, 1);
+            }
+            let mut text = Vec::new();
+            chunk.write_disassembly("test", &mut text).unwrap();
+            String::from_utf8(text).unwrap()
+        };
+        let constant = u8::from(OpCode::Constant);
+        assert!(disassembly(&[constant, 7]).contains("Index=7 <bad constant>"));
+        for op in [OpCode::Constant, OpCode::GetLocal, OpCode::Jump] {
+            let text = disassembly(&[op.into()]);
+            assert!(text.contains("<truncated>"), "{text}");
+        }
+        let loop_op = u8::from(OpCode::Loop);
+        assert!(disassembly(&[loop_op, 0, 9]).contains("0 -> <before start>"));
+    }
 }
@@ -1,11 +1,18 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+};
 
+use arcstr::ArcStr;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::Serialize;
 
 use crate::{
     chunk::{Chunk, OpCode},
-    scanner::{self, Token, TokenType},
-    value::Value,
+    scanner::{self, unescape, Token, TokenType},
+    value::{Function, Value},
     vm::InterpretError,
 };
 
@@ -14,6 +21,7 @@ use crate::{
 enum Precedence {
     None,
     Assignment,
+    Pipe,
     Or,
     And,
     Equality,
@@ -34,14 +42,74 @@ impl Precedence {
     }
 }
 
+#[derive(PartialEq)]
+enum FunctionType {
+    Function,
+    Method,
+    Script,
+}
+
+struct Local {
+    name: Token,
+    depth: Option<usize>,
+    // Whether the local's value is ever read, for the unused-variable warning.
+    read: bool,
+}
+
+struct Compiler {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    enclosing: Option<Box<Compiler>>,
+}
+
+impl Compiler {
+    pub fn init(function_type: FunctionType, name: &str) -> Self {
+        // Slot zero holds the function being called, or the receiver named
+        // `this` in a method.
+        let slot_zero = match function_type {
+            FunctionType::Method => Token::make_token(TokenType::This, "this", 0, 0),
+            _ => Token::make_token(TokenType::Identifier, "", 0, 0),
+        };
+        Compiler {
+            function: Function::init(name),
+            function_type,
+            locals: vec![Local {
+                name: slot_zero,
+                depth: Some(0),
+                read: true,
+            }],
+            scope_depth: 0,
+            enclosing: None,
+        }
+    }
+}
+
 struct Parser {
-    pub chunk: Chunk,
+    pub compiler: Compiler,
     pub current: Token,
     pub previous: Token,
     pub scanner: scanner::Scanner,
     pub had_error: bool,
     pub panic_mode: bool,
+    pub warnings: usize,
+    pub diagnostics: Vec<Diagnostic>,
+    // Category of the first error, which decides the exit code.
+    pub error_category: Option<ErrorCategory>,
+    // Globals declared with `const`, which may not be assigned or redeclared.
+    pub const_globals: HashSet<String>,
+    // Slot of every global declared so far, shared with the VM that runs the code.
+    pub global_slots: HashMap<String, usize>,
+    // Print how expressions are grouped while parsing them.
+    pub trace: bool,
+    pub optimize: bool,
+    pub trace_depth: usize,
     pub parse_rules: HashMap<TokenType, ParseRule>,
+    // Code offset where the left operand of the infix rule being compiled starts.
+    pub operand_start: usize,
+    // Code offset just past the most recently compiled assignment.
+    pub assignment_end: Option<usize>,
 }
 
 type ParseFn = fn(&mut Parser, can_assign: bool);
@@ -52,21 +120,34 @@ struct ParseRule {
 }
 
 impl Parser {
-    pub fn init(source: String) -> Self {
+    pub fn init(source: impl Into<ArcStr>) -> Self {
         Parser {
-            chunk: Chunk::init(),
-            current: Token::make_token(TokenType::Eof, "", 0),
-            previous: Token::make_token(TokenType::Eof, "", 0),
+            compiler: Compiler::init(FunctionType::Script, ""),
+            // Placeholders until the first `advance`, which moves `current` into
+            // `previous`. They sit at the start of the source so code emitted
+            // before any token is consumed gets line 1 rather than line 0.
+            current: Token::make_token(TokenType::Eof, "", 1, 1),
+            previous: Token::make_token(TokenType::Eof, "", 1, 1),
             scanner: scanner::Scanner::init(source),
             had_error: false,
             panic_mode: false,
+            warnings: 0,
+            diagnostics: Vec::new(),
+            error_category: None,
+            const_globals: HashSet::new(),
+            global_slots: HashMap::new(),
+            trace: false,
+            optimize: false,
+            trace_depth: 0,
+            operand_start: 0,
+            assignment_end: None,
             parse_rules: HashMap::from([
                 (
                     TokenType::Leftparen,
                     ParseRule {
                         prefix: Some(Self::grouping),
-                        infix: None,
-                        precedence: Precedence::None,
+                        infix: Some(Self::call),
+                        precedence: Precedence::Call,
                     },
                 ),
                 (
@@ -80,7 +161,7 @@ impl Parser {
                 (
                     TokenType::Leftbrace,
                     ParseRule {
-                        prefix: None,
+                        prefix: Some(Self::map),
                         infix: None,
                         precedence: Precedence::None,
                     },
@@ -93,6 +174,22 @@ impl Parser {
                         precedence: Precedence::None,
                     },
                 ),
+                (
+                    TokenType::Leftbracket,
+                    ParseRule {
+                        prefix: Some(Self::array),
+                        infix: Some(Self::index),
+                        precedence: Precedence::Call,
+                    },
+                ),
+                (
+                    TokenType::Rightbracket,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::Comma,
                     ParseRule {
@@ -103,6 +200,46 @@ impl Parser {
                 ),
                 (
                     TokenType::Dot,
+                    ParseRule {
+                        prefix: None,
+                        infix: Some(Self::dot),
+                        precedence: Precedence::Call,
+                    },
+                ),
+                (
+                    TokenType::Plusequal,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
+                (
+                    TokenType::Minusequal,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
+                (
+                    TokenType::Starequal,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
+                (
+                    TokenType::Slashequal,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
+                (
+                    TokenType::DotDot,
                     ParseRule {
                         prefix: None,
                         infix: None,
@@ -120,7 +257,7 @@ impl Parser {
                 (
                     TokenType::Plus,
                     ParseRule {
-                        prefix: None,
+                        prefix: Some(Self::unary),
                         infix: Some(Self::binary),
                         precedence: Precedence::Term,
                     },
@@ -133,6 +270,14 @@ impl Parser {
                         precedence: Precedence::None,
                     },
                 ),
+                (
+                    TokenType::Colon,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::Slash,
                     ParseRule {
@@ -149,6 +294,14 @@ impl Parser {
                         precedence: Precedence::Factor,
                     },
                 ),
+                (
+                    TokenType::Not,
+                    ParseRule {
+                        prefix: Some(Self::unary),
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::Bang,
                     ParseRule {
@@ -213,6 +366,14 @@ impl Parser {
                         precedence: Precedence::Comparison,
                     },
                 ),
+                (
+                    TokenType::PipeGreater,
+                    ParseRule {
+                        prefix: None,
+                        infix: Some(Self::pipe),
+                        precedence: Precedence::Pipe,
+                    },
+                ),
                 (
                     TokenType::Identifier,
                     ParseRule {
@@ -229,6 +390,14 @@ impl Parser {
                         precedence: Precedence::None,
                     },
                 ),
+                (
+                    TokenType::Bytes,
+                    ParseRule {
+                        prefix: Some(Self::string),
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::Number,
                     ParseRule {
@@ -239,6 +408,14 @@ impl Parser {
                 ),
                 (
                     TokenType::And,
+                    ParseRule {
+                        prefix: None,
+                        infix: Some(Self::and_),
+                        precedence: Precedence::And,
+                    },
+                ),
+                (
+                    TokenType::Class,
                     ParseRule {
                         prefix: None,
                         infix: None,
@@ -246,7 +423,7 @@ impl Parser {
                     },
                 ),
                 (
-                    TokenType::Class,
+                    TokenType::Do,
                     ParseRule {
                         prefix: None,
                         infix: None,
@@ -277,6 +454,30 @@ impl Parser {
                         precedence: Precedence::None,
                     },
                 ),
+                (
+                    TokenType::Foreach,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
+                (
+                    TokenType::In,
+                    ParseRule {
+                        prefix: None,
+                        infix: Some(Self::in_range),
+                        precedence: Precedence::Comparison,
+                    },
+                ),
+                (
+                    TokenType::Const,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::Fun,
                     ParseRule {
@@ -285,6 +486,14 @@ impl Parser {
                         precedence: Precedence::None,
                     },
                 ),
+                (
+                    TokenType::Global,
+                    ParseRule {
+                        prefix: None,
+                        infix: None,
+                        precedence: Precedence::None,
+                    },
+                ),
                 (
                     TokenType::If,
                     ParseRule {
@@ -305,8 +514,8 @@ impl Parser {
                     TokenType::Or,
                     ParseRule {
                         prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
+                        infix: Some(Self::or_),
+                        precedence: Precedence::Or,
                     },
                 ),
                 (
@@ -336,7 +545,7 @@ impl Parser {
                 (
                     TokenType::This,
                     ParseRule {
-                        prefix: None,
+                        prefix: Some(Self::this),
                         infix: None,
                         precedence: Precedence::None,
                     },
@@ -389,46 +598,77 @@ impl Parser {
         std::mem::swap(&mut self.previous, &mut self.current);
 
         loop {
-            self.current = self.scanner.scan_token();
+            // Past the end the scanner yields nothing; stay on the `Eof` token.
+            self.current = match self.scanner.next() {
+                Some(token) => token,
+                None => self.previous.clone(),
+            };
             if self.current.token_type != TokenType::Error {
                 break;
             }
 
             let error = self.current.lexeme.to_string();
+            self.error_category.get_or_insert(ErrorCategory::Scan);
             self.error_at_current(error.as_str());
         }
     }
 
     fn error_at_current(&mut self, message: &str) {
         self.had_error = true;
+        self.error_category.get_or_insert(ErrorCategory::Parse);
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
 
-        let token: &Token = &self.current;
-        self.error_at(token, message);
+        let token = self.current.clone();
+        self.report_at(&token, Severity::Error, message);
     }
 
     fn error(&mut self, message: &str) {
         self.had_error = true;
+        self.error_category.get_or_insert(ErrorCategory::Parse);
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
 
-        let token: &Token = &self.previous;
-        self.error_at(token, message);
+        let token = self.previous.clone();
+        self.report_at(&token, Severity::Error, message);
+    }
+
+    // Reports a name that can't be declared, assigned or read where it is used.
+    fn resolve_error(&mut self, message: &str) {
+        self.error_category.get_or_insert(ErrorCategory::Resolve);
+        self.error(message);
+    }
+
+    fn warning_at_current(&mut self, message: &str) {
+        let token = self.current.clone();
+        self.warning_at(&token, message);
     }
 
-    fn error_at(&self, token: &Token, message: &str) {
-        let error_loc = match token.token_type {
+    fn warning_at(&mut self, token: &Token, message: &str) {
+        self.warnings += 1;
+        self.report_at(token, Severity::Warning, message);
+    }
+
+    // Records a diagnostic for whoever called `compile` to print.
+    fn report_at(&mut self, token: &Token, severity: Severity, message: &str) {
+        let location = match token.token_type {
             TokenType::Eof => "at end".to_string(),
             TokenType::Error => "".to_string(),
             _ => format!("at '{}'", token.lexeme),
         };
 
-        eprintln!("[line {}] Error {}: {}", token.line, error_loc, message);
+        self.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.column,
+            message: message.to_string(),
+            severity,
+            location,
+            source_line: self.scanner.source_line(token.line).map(str::to_string),
+        });
     }
 
     fn consume(&mut self, tt: TokenType, msg: &str) {
@@ -452,19 +692,64 @@ impl Parser {
     }
 
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.chunk
+        &mut self.compiler.function.chunk
     }
 
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn end_compilation(&mut self) {
+    fn end_compilation(&mut self) -> Function {
         self.emit_return();
+
+        let mut compiler = match self.compiler.enclosing.take() {
+            Some(enclosing) => std::mem::replace(&mut self.compiler, *enclosing),
+            None => std::mem::replace(&mut self.compiler, Compiler::init(FunctionType::Script, "")),
+        };
+
+        if self.optimize {
+            optimize(&mut compiler.function.chunk);
+        }
+
+        if cfg!(debug_assertions) && !self.had_error {
+            let name = match compiler.function_type {
+                FunctionType::Script => "code",
+                FunctionType::Function | FunctionType::Method => &compiler.function.name,
+            };
+            compiler.function.chunk.disassemble(name);
+        }
+
+        compiler.function
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_bytes(&[op.into(), 0xff, 0xff]);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let code = &mut self.current_chunk().code;
+        code[offset] = ((jump >> 8) & 0xff) as u8;
+        code[offset + 1] = (jump & 0xff) as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop.into());
+
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit_bytes(&[((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8]);
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return.into());
+        self.emit_bytes(&[OpCode::Nil.into(), OpCode::Return.into()]);
     }
 
     fn number(&mut self, _can_assign: bool) {
@@ -474,12 +759,47 @@ impl Parser {
         }
     }
 
+    // Common literals get dedicated opcodes that skip the constant pool.
     fn emit_constant(&mut self, value: Value) {
+        let fast_op = match value {
+            Value::Number(n) if n == 0.0 && n.is_sign_positive() => Some(OpCode::Constant0),
+            Value::Number(1.0) => Some(OpCode::Constant1),
+            Value::Number(-1.0) => Some(OpCode::ConstantMinus1),
+            Value::Boolean(true) => Some(OpCode::True),
+            Value::Boolean(false) => Some(OpCode::False),
+            Value::Nil => Some(OpCode::Nil),
+            _ => None,
+        };
+        if let Some(op) = fast_op {
+            self.emit_byte(op.into());
+            return;
+        }
+
         let op: u8 = OpCode::Constant.into();
         let index: u8 = self.make_constant(value);
         self.emit_bytes(&[op, index])
     }
 
+    // The number pushed by the code from `start` on, when that code is a single
+    // number literal whose constant, if it has one, was the last added.
+    fn literal_number(&mut self, start: usize) -> Option<f32> {
+        let chunk = self.current_chunk();
+        match chunk.code[start..] {
+            [op] if op == u8::from(OpCode::Constant1) => Some(1.0),
+            [op] if op == u8::from(OpCode::ConstantMinus1) => Some(-1.0),
+            [op, index]
+                if op == u8::from(OpCode::Constant)
+                    && index as usize + 1 == chunk.constants.values.len() =>
+            {
+                match chunk.constant(index as usize) {
+                    Some(Value::Number(n)) => Some(*n),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
     fn make_constant(&mut self, value: Value) -> u8 {
         let index = self.current_chunk().add_constant(value);
         if index > u8::MAX as usize {
@@ -491,24 +811,63 @@ impl Parser {
     }
 
     fn grouping(&mut self, _can_assign: bool) {
+        self.trace("grouping");
         self.expression();
         self.consume(TokenType::Rightparen, "Expect ')' after expression.");
     }
 
     fn unary(&mut self, _can_assign: bool) {
         let op_type = self.previous.token_type.clone();
+        self.trace(&format!("unary '{}'", self.previous.lexeme));
 
+        let start = self.current_chunk().code.len();
         self.parse_precedence(Precedence::Unary);
 
         match op_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate.into()),
-            TokenType::Bang => self.emit_byte(OpCode::Not.into()),
+            TokenType::Minus => {
+                // A negated number literal becomes the negative literal, which
+                // lets `-1` use its own opcode.
+                match self.literal_number(start) {
+                    Some(n) => {
+                        let chunk = self.current_chunk();
+                        if chunk.code.len() - start == 2 {
+                            chunk.constants.pop();
+                        }
+                        chunk.truncate(start);
+                        self.emit_constant(Value::Number(-n));
+                    }
+                    _ => self.emit_byte(OpCode::Negate.into()),
+                }
+            }
+            TokenType::Plus => self.emit_byte(OpCode::AssertNumber.into()),
+            // Both forms are logical negation on truthiness, even for integers.
+            TokenType::Bang | TokenType::Not => {
+                // `!true`, `!false` and `!nil` become the negated literal.
+                let optimize = self.optimize;
+                let folded = match self.current_chunk().code[start..] {
+                    [op] if optimize && op == u8::from(OpCode::True) => Some(OpCode::False),
+                    [op] if optimize
+                        && (op == u8::from(OpCode::False) || op == u8::from(OpCode::Nil)) =>
+                    {
+                        Some(OpCode::True)
+                    }
+                    _ => None,
+                };
+                match folded {
+                    Some(literal) => {
+                        self.current_chunk().truncate(start);
+                        self.emit_byte(literal.into());
+                    }
+                    None => self.emit_byte(OpCode::Not.into()),
+                }
+            }
             _ => unreachable!(),
         }
     }
 
     fn binary(&mut self, _can_assign: bool) {
         let op_type = self.previous.token_type.clone();
+        self.trace(&format!("binary '{}'", self.previous.lexeme));
         let rule = self.get_rule(&op_type);
         self.parse_precedence(Precedence::higher_precedence(rule.precedence.clone()));
 
@@ -527,6 +886,169 @@ impl Parser {
         }
     }
 
+    // `x |> f` calls `f(x)`. The argument is already on the stack, so the
+    // callee is swapped beneath it to match the usual call layout.
+    fn pipe(&mut self, _can_assign: bool) {
+        self.parse_precedence(Precedence::higher_precedence(Precedence::Pipe));
+        self.emit_bytes(&[OpCode::Swap.into(), OpCode::Call.into(), 1]);
+    }
+
+    // `x in lo..hi` is `lo <= x and x < hi` with `x` evaluated once. The
+    // value is kept beneath the first comparison and dropped afterwards.
+    fn in_range(&mut self, _can_assign: bool) {
+        self.emit_byte(OpCode::Dup.into());
+        self.parse_precedence(Precedence::Term);
+        self.emit_byte(OpCode::CheckNumber.into());
+        self.consume(TokenType::DotDot, "Expect '..' in range.");
+        self.emit_bytes(&[OpCode::Less.into(), OpCode::Not.into()]);
+
+        let below_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.parse_precedence(Precedence::Term);
+        self.emit_bytes(&[OpCode::CheckNumber.into(), OpCode::Less.into()]);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(below_jump);
+        self.emit_bytes(&[OpCode::Swap.into(), OpCode::Pop.into()]);
+        self.patch_jump(end_jump);
+    }
+
+    fn and_(&mut self, _can_assign: bool) {
+        // `false and x` is always false, so x is never compiled in.
+        if self.optimize && self.left_operand_is(OpCode::False) {
+            self.skip_operand(Precedence::And);
+            return;
+        }
+
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn or_(&mut self, _can_assign: bool) {
+        // `true or x` is always true, so x is never compiled in.
+        if self.optimize && self.left_operand_is(OpCode::True) {
+            self.skip_operand(Precedence::Or);
+            return;
+        }
+
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn left_operand_is(&mut self, op: OpCode) -> bool {
+        let start = self.operand_start;
+        self.current_chunk().code[start..] == [u8::from(op)]
+    }
+
+    // Parses the right operand for errors, then drops the code it emitted.
+    fn skip_operand(&mut self, precedence: Precedence) {
+        let len = self.current_chunk().code.len();
+        self.parse_precedence(precedence);
+        self.current_chunk().truncate(len);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(&[OpCode::Call.into(), arg_count]);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let arg_count = self.comma_separated(TokenType::Rightparen, "arguments", Self::expression);
+        self.consume(TokenType::Rightparen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    fn array(&mut self, _can_assign: bool) {
+        let count =
+            self.comma_separated(TokenType::Rightbracket, "array elements", Self::expression);
+        self.consume(TokenType::Rightbracket, "Expect ']' after array elements.");
+        self.emit_bytes(&[OpCode::BuildArray.into(), count]);
+    }
+
+    // A `{` in expression position starts a map literal such as `{"a": 1}`.
+    fn map(&mut self, _can_assign: bool) {
+        let count = self.comma_separated(TokenType::Rightbrace, "map entries", Self::map_entry);
+        self.consume(TokenType::Rightbrace, "Expect '}' after map entries.");
+        self.emit_bytes(&[OpCode::BuildMap.into(), count]);
+    }
+
+    fn map_entry(&mut self) {
+        self.expression();
+        self.consume(TokenType::Colon, "Expect ':' after map key.");
+        self.expression();
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(&[OpCode::SetProperty.into(), name]);
+            self.assignment_end = Some(self.current_chunk().code.len());
+        } else {
+            self.emit_bytes(&[OpCode::GetProperty.into(), name]);
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::Rightbracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetIndex.into());
+            self.assignment_end = Some(self.current_chunk().code.len());
+        } else if let Some(op) = self.match_compound_assignment().filter(|_| can_assign) {
+            // The container and index are copied rather than evaluated again.
+            self.emit_bytes(&[OpCode::Dup2.into(), OpCode::GetIndex.into()]);
+            self.expression();
+            self.emit_bytes(&[op.into(), OpCode::SetIndex.into()]);
+            self.assignment_end = Some(self.current_chunk().code.len());
+        } else {
+            self.emit_byte(OpCode::GetIndex.into());
+        }
+    }
+
+    // Consumes `+=`, `-=`, `*=` or `/=`, returning the arithmetic it stands for.
+    fn match_compound_assignment(&mut self) -> Option<OpCode> {
+        let op = match self.current.token_type {
+            TokenType::Plusequal => OpCode::Add,
+            TokenType::Minusequal => OpCode::Subtract,
+            TokenType::Starequal => OpCode::Multiply,
+            TokenType::Slashequal => OpCode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    // Compiles comma separated expressions up to the closing token, allowing a
+    // trailing comma before it.
+    fn comma_separated(&mut self, closing: TokenType, what: &str, element: fn(&mut Self)) -> u8 {
+        let mut count: u8 = 0;
+        while !self.check(closing.clone()) {
+            element(self);
+            if count == u8::MAX {
+                self.error(&format!("Can't have more than 255 {what}."));
+            } else {
+                count += 1;
+            }
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        count
+    }
+
     fn literal(&mut self, _can_assign: bool) {
         match self.previous.token_type {
             TokenType::False => self.emit_byte(OpCode::False.into()),
@@ -537,12 +1059,42 @@ impl Parser {
     }
 
     fn string(&mut self, _can_assign: bool) {
-        let v = Value::DynamicString(self.previous.lexeme.to_string());
+        // Strip the surrounding quotes and the `b` prefix of byte strings. The
+        // scanner has already rejected malformed escapes.
+        let lexeme = &self.previous.lexeme;
+        let v = match self.previous.token_type {
+            TokenType::Bytes => {
+                Value::Bytes(unescape(&lexeme[2..lexeme.len() - 1], true).unwrap_or_default())
+            }
+            _ => {
+                let unescaped = unescape(&lexeme[1..lexeme.len() - 1], false).unwrap_or_default();
+                Value::DynamicString(String::from_utf8_lossy(&unescaped).into_owned())
+            }
+        };
         self.emit_constant(v);
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        self.trace(&format!(
+            "parse_precedence({:?}) at '{}'",
+            precedence, self.current.lexeme
+        ));
+        self.trace_depth += 1;
+        self.climb_precedence(precedence.clone());
+        self.trace_depth -= 1;
+        self.trace(&format!("end {:?}", precedence));
+    }
+
+    fn trace(&self, message: &str) {
+        if self.trace {
+            eprintln!("{}{}", "  ".repeat(self.trace_depth), message);
+        }
+    }
+
+    fn climb_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        let start = self.previous.clone();
+        let start_offset = self.current_chunk().code.len();
 
         let precedence: u8 = precedence.into();
         let can_assign: bool = precedence <= Precedence::Assignment.into();
@@ -566,12 +1118,18 @@ impl Parser {
             self.advance();
             let infix_rule = self.get_rule(&self.previous.token_type).infix;
             if let Some(rule) = infix_rule {
+                self.operand_start = start_offset;
                 rule(self, can_assign);
             }
         }
 
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.error("Invalid assignment target");
+        if can_assign
+            && (self.match_token(TokenType::Equal) || self.match_compound_assignment().is_some())
+        {
+            self.error(&format!(
+                "Invalid assignment target starting at '{}'.",
+                start.lexeme
+            ));
         }
     }
 
@@ -589,8 +1147,14 @@ impl Parser {
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::Var) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
             self.variable_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -603,110 +1167,1200 @@ impl Parser {
     fn statement(&mut self) {
         if self.match_token(TokenType::Print) {
             self.print_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::Global) {
+            self.global_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::Do) {
+            self.do_while_statement();
+        } else if self.match_token(TokenType::Foreach) {
+            self.foreach_statement();
+        } else if self.match_token(TokenType::Leftbrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
         } else {
             self.expression_statement();
         }
     }
 
-    fn print_statement(&mut self) {
+    // Compiles a branch or loop condition up to its closing paren. A bare
+    // assignment there is almost always a mistyped `==`, so it gets a warning.
+    fn condition(&mut self) {
+        self.assignment_end = None;
         self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Print.into());
+        let end = self.current_chunk().code.len();
+        if self.assignment_end == Some(end) {
+            self.warning_at_current("Assignment used as a condition; did you mean '=='?");
+        }
+        self.consume(TokenType::Rightparen, "Expect ')' after condition.");
     }
 
-    fn check(&self, t: TokenType) -> bool {
-        self.current.token_type == t
+    fn if_statement(&mut self) {
+        self.consume(TokenType::Leftparen, "Expect '(' after 'if'.");
+        self.condition();
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop.into());
+
+        // `else if` chains nest to the right, each with its own jumps.
+        if self.match_token(TokenType::Else) {
+            if self.match_token(TokenType::If) {
+                self.if_statement();
+            } else {
+                self.statement();
+            }
+        }
+        self.patch_jump(else_jump);
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::Leftparen, "Expect '(' after 'while'.");
+        self.condition();
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop.into());
     }
 
-    fn synchronize(&mut self) {
-        self.panic_mode = false;
+    fn do_while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.statement();
 
-        while self.current.token_type != TokenType::Eof {
-            if self.previous.token_type == TokenType::Semicolon {
-                return;
-            }
+        self.consume(TokenType::While, "Expect 'while' after 'do' body.");
+        self.consume(TokenType::Leftparen, "Expect '(' after 'while'.");
+        self.condition();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after 'do' loop condition.",
+        );
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+    }
+
+    // Iterates with two hidden locals holding the array and the current index.
+    fn foreach_statement(&mut self) {
+        self.consume(TokenType::Leftparen, "Expect '(' after 'foreach'.");
+        self.consume(TokenType::Identifier, "Expect loop variable name.");
+        let item = self.previous.clone();
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+
+        self.begin_scope();
+        self.expression();
+        let array = self.add_hidden_local(" foreach array");
+        self.emit_constant(Value::Number(0.0));
+        let index = self.add_hidden_local(" foreach index");
+        self.consume(TokenType::Rightparen, "Expect ')' after foreach clause.");
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_bytes(&[OpCode::GetLocal.into(), index]);
+        self.emit_bytes(&[OpCode::GetLocal.into(), array]);
+        self.emit_bytes(&[OpCode::Length.into(), OpCode::Less.into()]);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+
+        self.begin_scope();
+        self.emit_bytes(&[OpCode::GetLocal.into(), array]);
+        self.emit_bytes(&[OpCode::GetLocal.into(), index]);
+        self.emit_byte(OpCode::GetIndex.into());
+        self.add_local(item);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+
+        self.emit_bytes(&[OpCode::GetLocal.into(), index]);
+        self.emit_constant(Value::Number(1.0));
+        self.emit_bytes(&[
+            OpCode::Add.into(),
+            OpCode::SetLocal.into(),
+            index,
+            OpCode::Pop.into(),
+        ]);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.end_scope();
+    }
+
+    fn block(&mut self) {
+        self.declarations(TokenType::Rightbrace);
+        self.consume(TokenType::Rightbrace, "Expect '}' after block.");
+    }
+
+    // Compiles declarations up to `end`, warning once about code after a return.
+    fn declarations(&mut self, end: TokenType) {
+        let mut returned = false;
+        let mut warned = false;
+        while !self.check(end.clone()) && !self.check(TokenType::Eof) {
+            if returned && !warned {
+                self.warning_at_current("Unreachable code.");
+                warned = true;
+            }
+            returned |= self.check(TokenType::Return);
+            self.declaration();
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        let scope_depth = self.compiler.scope_depth;
+        let first = self
+            .compiler
+            .locals
+            .iter()
+            .rposition(|local| local.depth.unwrap_or(0) <= scope_depth)
+            .map_or(0, |i| i + 1);
+        self.warn_unused_locals(first);
+
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth.unwrap_or(0) <= self.compiler.scope_depth {
+                break;
+            }
+
+            self.emit_byte(OpCode::Pop.into());
+            self.compiler.locals.pop();
+        }
+    }
+
+    // Warns about each local from `first` on that is never read, in the order
+    // they were declared. Locals named `_` are exempt.
+    fn warn_unused_locals(&mut self, first: usize) {
+        let unused: Vec<Token> = self.compiler.locals[first.min(self.compiler.locals.len())..]
+            .iter()
+            .filter(|local| !local.read && local.name.lexeme != "_")
+            .map(|local| local.name.clone())
+            .collect();
+        for name in unused {
+            self.warning_at(&name, &format!("Unused variable '{}'.", name.lexeme));
+        }
+    }
+
+    // A top-level return ends the script, yielding its value as the exit code.
+    fn return_statement(&mut self) {
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return.into());
+        }
+    }
+
+    // `global x = value;` assigns the global `x` even where a local `x`
+    // shadows it. Like any assignment, the global must already be defined.
+    fn global_statement(&mut self) {
+        self.consume(
+            TokenType::Identifier,
+            "Expect variable name after 'global'.",
+        );
+        let name = self.previous.clone();
+        self.consume(TokenType::Equal, "Expect '=' after global name.");
+        if self.const_globals.contains(name.lexeme.as_str()) {
+            self.resolve_error(&format!("Cannot assign to const '{}'.", name.lexeme));
+        }
+        self.expression();
+
+        let slot = self
+            .global_slots
+            .get(name.lexeme.as_str())
+            .and_then(|slot| u8::try_from(*slot).ok());
+        match slot {
+            Some(slot) => self.emit_named(OpCode::SetGlobalFast, slot, &name),
+            None => {
+                let constant = self.identifier_constant(&name);
+                self.emit_bytes(&[OpCode::SetGlobal.into(), constant]);
+            }
+        }
+        self.emit_byte(OpCode::Pop.into());
+        self.consume(TokenType::Semicolon, "Expect ';' after global assignment.");
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print.into());
+    }
+
+    fn check(&self, t: TokenType) -> bool {
+        self.current.token_type == t
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop.into());
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::Eof {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
 
             match self.current.token_type {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Do
+                | TokenType::Foreach
                 | TokenType::Print
+                | TokenType::Global
                 | TokenType::Return => return,
                 _ => self.advance(),
             }
         }
     }
 
+    fn class_declaration(&mut self) {
+        let global = self.parse_variable("Expect class name.");
+        let name = self.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+        self.emit_bytes(&[OpCode::Class.into(), name_constant]);
+        self.define_variable(global);
+
+        // The class goes back on the stack while its methods are attached.
+        self.named_variable(name, false);
+        self.consume(TokenType::Leftbrace, "Expect '{' before class body.");
+        while !self.check(TokenType::Rightbrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::Rightbrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop.into());
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+        self.function(FunctionType::Method);
+        self.emit_bytes(&[OpCode::Method.into(), name_constant]);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let compiler = Compiler::init(function_type, &self.previous.lexeme);
+        let enclosing = std::mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        self.begin_scope();
+
+        self.consume(TokenType::Leftparen, "Expect '(' after function name.");
+        if !self.check(TokenType::Rightparen) {
+            loop {
+                if self.compiler.function.arity == u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                self.compiler.function.arity += 1;
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::Rightparen, "Expect ')' after parameters.");
+        self.consume(TokenType::Leftbrace, "Expect '{' before function body.");
+        self.block();
+        // The body shares the parameters' scope, which is never ended.
+        self.warn_unused_locals(1 + self.compiler.function.arity);
+
+        let function = self.end_compilation();
+        self.emit_constant(Value::Function(Rc::new(function)));
+    }
+
+    // `var a = 1, b, c = 3;` defines each variable in turn.
     fn variable_declaration(&mut self) {
-        let global = self.parse_variable("Expect a variable name.");
+        loop {
+            let global = self.parse_variable("Expect a variable name.");
 
-        if self.match_token(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.emit_byte(OpCode::Nil.into());
+            if self.match_token(TokenType::Equal) {
+                self.expression();
+            } else {
+                self.emit_byte(OpCode::Nil.into());
+            }
+
+            self.define_variable(global);
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
         }
 
         self.consume(
             TokenType::Semicolon,
             "Expect ';' after variable declaration",
         );
+    }
 
-        self.define_variable(global);
+    fn const_declaration(&mut self) {
+        if self.compiler.scope_depth > 0 {
+            self.error("Const declarations are only allowed at the top level.");
+        }
+        let global = self.parse_variable("Expect a constant name.");
+        let name = self.previous.lexeme.to_string();
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        if self.compiler.scope_depth > 0 {
+            self.define_variable(global);
+        } else {
+            self.emit_bytes(&[OpCode::DefineGlobalConst.into(), global]);
+        }
+        self.const_globals.insert(name);
     }
 
     fn parse_variable(&mut self, error: &str) -> u8 {
         self.consume(TokenType::Identifier, error);
-        self.identifier_constant(&self.previous.clone())
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous.clone();
+        let next = self.global_slots.len();
+        self.global_slots
+            .entry(name.lexeme.to_string())
+            .or_insert(next);
+        self.identifier_constant(&name)
+    }
+
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            if self
+                .const_globals
+                .contains(&self.previous.lexeme.to_string())
+            {
+                self.resolve_error(&format!(
+                    "Cannot redeclare const '{}'.",
+                    self.previous.lexeme
+                ));
+            }
+            return;
+        }
+
+        let name = self.previous.clone();
+        let scope_depth = self.compiler.scope_depth;
+        let already_declared = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name.lexeme == name.lexeme);
+
+        if already_declared {
+            self.resolve_error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.compiler.locals.len() > u8::MAX as usize {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            read: false,
+        });
+    }
+
+    // Adds an initialized local whose name can't clash with an identifier.
+    fn add_hidden_local(&mut self, name: &str) -> u8 {
+        let token = Token::make_token(TokenType::Identifier, name, self.previous.line, 0);
+        self.add_local(token);
+        self.mark_initialized();
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.read = true;
+        }
+        (self.compiler.locals.len() - 1) as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.compiler.scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
     }
 
     fn define_variable(&mut self, global: u8) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
         self.emit_bytes(&[OpCode::DefineGlobal.into(), global]);
     }
 
+    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+        let (slot, local) = self
+            .compiler
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)?;
+
+        if local.depth.is_none() {
+            self.resolve_error("Can't read local variable in its own initializer.");
+        }
+
+        Some(slot as u8)
+    }
+
     fn identifier_constant(&mut self, t: &Token) -> u8 {
         self.make_constant(Value::DynamicString(t.lexeme.to_string()))
     }
 
+    fn this(&mut self, _can_assign: bool) {
+        if self.compiler.function_type != FunctionType::Method {
+            self.resolve_error("Can't use 'this' outside of a method.");
+            return;
+        }
+        self.variable(false);
+    }
+
     fn variable(&mut self, can_assign: bool) {
         self.named_variable(self.previous.clone(), can_assign);
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let arg = self.identifier_constant(&name);
+        let global_slot = self
+            .global_slots
+            .get(name.lexeme.as_str())
+            .and_then(|slot| u8::try_from(*slot).ok());
+        let (get_op, set_op, arg) = match (self.resolve_local(&name), global_slot) {
+            (Some(slot), _) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            (None, Some(slot)) => (OpCode::GetGlobalFast, OpCode::SetGlobalFast, slot),
+            // Not declared yet (or past the last one-byte slot), so the VM
+            // looks the global up by name when the code runs.
+            (None, None) => (
+                OpCode::GetGlobal,
+                OpCode::SetGlobal,
+                self.identifier_constant(&name),
+            ),
+        };
+
         if can_assign && self.match_token(TokenType::Equal) {
+            if matches!(set_op, OpCode::SetGlobal | OpCode::SetGlobalFast)
+                && self.const_globals.contains(&name.lexeme.to_string())
+            {
+                self.resolve_error(&format!("Cannot assign to const '{}'.", name.lexeme));
+            }
+            self.expression();
+            self.emit_named(set_op, arg, &name);
+            self.assignment_end = Some(self.current_chunk().code.len());
+        } else if let Some(op) = self.match_compound_assignment().filter(|_| can_assign) {
+            if matches!(set_op, OpCode::SetGlobal | OpCode::SetGlobalFast)
+                && self.const_globals.contains(&name.lexeme.to_string())
+            {
+                self.resolve_error(&format!("Cannot assign to const '{}'.", name.lexeme));
+            }
+            if get_op == OpCode::GetLocal {
+                self.compiler.locals[arg as usize].read = true;
+            }
+            self.emit_named(get_op, arg, &name);
             self.expression();
-            self.emit_bytes(&[OpCode::SetGlobal.into(), arg]);
+            self.emit_byte(op.into());
+            self.emit_named(set_op, arg, &name);
+            self.assignment_end = Some(self.current_chunk().code.len());
         } else {
-            self.emit_bytes(&[OpCode::GetGlobal.into(), arg]);
+            if get_op == OpCode::GetLocal {
+                self.compiler.locals[arg as usize].read = true;
+            }
+            self.emit_named(get_op, arg, &name);
         }
     }
+
+    // Emits a variable access, noting the name for slot-based instructions in
+    // debug builds.
+    fn emit_named(&mut self, op: OpCode, arg: u8, name: &Token) {
+        if cfg!(debug_assertions) && !op.has_constant_operand() {
+            let offset = self.current_chunk().code.len();
+            self.current_chunk().add_debug_name(offset, &name.lexeme);
+        }
+        self.emit_bytes(&[op.into(), arg]);
+    }
+}
+
+// The stage of compilation an error comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    // A character or literal the scanner can't turn into a token.
+    Scan,
+    Parse,
+    // A misused name, such as assigning to a const.
+    Resolve,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// An error or warning reported while compiling, pointing at a source token.
+// Serializes to the fields editors need; the rest is for `print`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub severity: Severity,
+    // The offending token as shown in the message, e.g. `at 'x'` or `at end`.
+    #[serde(skip)]
+    pub location: String,
+    // The text of the line, for pointing at the column under it.
+    #[serde(skip)]
+    pub source_line: Option<String>,
 }
 
-pub fn compile(source: String) -> Result<Chunk, InterpretError> {
+impl Diagnostic {
+    // An error with no token to point at.
+    pub fn error(line: usize, message: String) -> Self {
+        Diagnostic {
+            line,
+            column: 0,
+            message,
+            severity: Severity::Error,
+            location: String::new(),
+            source_line: None,
+        }
+    }
+
+    pub fn print(&self) {
+        self.write_to(&mut io::stderr())
+            .expect("Failed to write diagnostic");
+    }
+
+    // Writes the diagnostic with the source line and a caret under the column.
+    // Ones without a line, like bytecode load failures, are just the message.
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.line == 0 {
+            return writeln!(out, "{}", self.message);
+        }
+
+        let kind = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        writeln!(
+            out,
+            "[line {}] {} {}: {}",
+            self.line, kind, self.location, self.message
+        )?;
+
+        // Warnings stay on one line; only errors point into the source.
+        if self.severity != Severity::Error {
+            return Ok(());
+        }
+        if let Some(source_line) = &self.source_line {
+            let indent: String = source_line
+                .chars()
+                .take(self.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(out, "    {source_line}")?;
+            writeln!(out, "    {indent}^")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}, column {}] {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CompileOptions {
+    // Fail compilation when any warning was reported.
+    pub werror: bool,
+    pub parse_trace: bool,
+    // Scan `print` as an identifier so it resolves to the `print` native.
+    pub print_as_fn: bool,
+    // Run the optimization passes; without it codegen is a direct translation.
+    pub optimize: bool,
+    // Lines of the file that come before this source, when compiling it in pieces.
+    pub line_offset: usize,
+}
+
+// Passes over a finished chunk. Folding of constant `and`/`or` operands and
+// of `!` on literals happens during codegen instead, under the same option.
+fn optimize(chunk: &mut Chunk) {
+    chunk.coalesce_pops();
+}
+
+// A successfully compiled script and the warnings reported along the way.
+pub struct Compiled {
+    pub function: Function,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Compiles a script, resolving globals against `global_slots` and adding the
+// ones it declares, so the VM that owns the slots can index them directly.
+// Nothing is printed; errors come back in the `CompileError` and warnings
+// with the compiled function.
+pub fn compile(
+    source: impl Into<ArcStr>,
+    options: &CompileOptions,
+    global_slots: &mut HashMap<String, usize>,
+) -> Result<Compiled, InterpretError> {
     let mut parser = Parser::init(source);
+    parser.global_slots = std::mem::take(global_slots);
+    parser.trace = options.parse_trace;
+    parser.scanner.start_at_line(1 + options.line_offset);
+    parser.optimize = options.optimize;
+    if options.print_as_fn {
+        parser.scanner.remove_keyword("print");
+    }
     parser.advance();
 
-    while !parser.match_token(TokenType::Eof) {
-        parser.declaration();
-    }
+    parser.declarations(TokenType::Eof);
 
-    parser.end_compilation();
-    if parser.had_error {
-        Err(InterpretError::CompileError)
+    let function = parser.end_compilation();
+    *global_slots = std::mem::take(&mut parser.global_slots);
+    if parser.had_error || (options.werror && parser.warnings > 0) {
+        if !parser.had_error {
+            let message = format!("{} warning(s) treated as errors.", parser.warnings);
+            parser.diagnostics.push(Diagnostic::error(0, message));
+        }
+        Err(InterpretError::CompileError {
+            diagnostics: parser.diagnostics,
+            category: parser.error_category,
+        })
     } else {
-        if cfg!(debug_assertions) {
-            parser.chunk.disassemble("code");
+        Ok(Compiled {
+            function,
+            warnings: parser.diagnostics,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{compile, compile_errors, output, run_with, runtime_error};
+
+    fn optimized() -> CompileOptions {
+        CompileOptions {
+            optimize: true,
+            ..Default::default()
+        }
+    }
+
+    fn ops(function: &Function) -> Vec<OpCode> {
+        function.chunk.instructions().map(|(_, op, _)| op).collect()
+    }
+
+    #[test]
+    fn invalid_assignment_target_names_where_it_starts() {
+        let errors = compile_errors("1 = 2;");
+        assert_eq!(
+            errors[0].message,
+            "Invalid assignment target starting at '1'."
+        );
+
+        let errors = compile_errors("var a; (a) = 2;");
+        assert_eq!(
+            errors[0].message,
+            "Invalid assignment target starting at '('."
+        );
+    }
+
+    #[test]
+    fn locals_leaving_scope_are_popped_at_once() {
+        let function = compile("{ var a = 1; var b = 2; var c = 3; }", &optimized());
+        let code = &function.chunk.code;
+        let pop_n = code.len() - 4;
+        assert_eq!(code[pop_n..pop_n + 2], [OpCode::PopN.into(), 3]);
+        assert!(!ops(&function).contains(&OpCode::Pop));
+    }
+
+    #[test]
+    fn trailing_commas_are_allowed_in_arguments_and_arrays() {
+        assert_eq!(
+            output("fun f(a, b) { return a + b; } print f(1, 2,); print [1, 2,];"),
+            "3\n[1, 2]\n"
+        );
+    }
+
+    #[test]
+    fn a_comma_needs_an_element_before_it() {
+        for source in ["fun f(a) {} f(,);", "print [,];", "print [1,,];"] {
+            let errors = compile_errors(source);
+            assert_eq!(errors[0].message, "Expect expression.", "{source}");
+            assert_eq!(errors[0].location, "at ','", "{source}");
+        }
+    }
+
+    #[test]
+    fn do_while_runs_its_body_before_checking() {
+        assert_eq!(output("do { print 1; } while (false);"), "1\n");
+        assert_eq!(
+            output("var i = 0; do { i = i + 1; } while (i < 3); print i;"),
+            "3\n"
+        );
+    }
+
+    #[test]
+    fn foreach_visits_each_element() {
+        assert_eq!(
+            output("var sum = 0; foreach (x in [1, 2, 3]) { sum = sum + x; } print sum;"),
+            "6\n"
+        );
+        assert_eq!(
+            output("foreach (x in []) { print x; } print \"done\";"),
+            "done\n"
+        );
+    }
+
+    #[test]
+    fn literal_left_operands_fold_and_and_or() {
+        for source in ["var x; print false and x;", "var x; print true or x;"] {
+            let code = ops(&compile(source, &optimized()));
+            assert_eq!(
+                code[code.len() - 3..],
+                [OpCode::Print, OpCode::Nil, OpCode::Return]
+            );
+            assert!(!code.contains(&OpCode::GetGlobalFast), "{source}");
+            assert!(!code.contains(&OpCode::JumpIfFalse), "{source}");
+
+            let code = ops(&compile(source, &CompileOptions::default()));
+            assert!(code.contains(&OpCode::GetGlobalFast), "{source}");
+        }
+        assert_eq!(
+            output("print false and 1; print true or 1;"),
+            "false\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn other_literal_left_operands_still_short_circuit() {
+        for source in ["var x; print true and x;", "var x; print false or x;"] {
+            let code = ops(&compile(source, &optimized()));
+            assert!(code.contains(&OpCode::JumpIfFalse), "{source}");
+            assert!(code.contains(&OpCode::GetGlobalFast), "{source}");
+        }
+    }
+
+    #[test]
+    fn werror_fails_the_compile_on_warnings() {
+        let source = "fun f() { return 1; print 2; }";
+        let mut slots = HashMap::new();
+        let compiled = super::compile(source, &CompileOptions::default(), &mut slots).unwrap();
+        assert_eq!(compiled.warnings.len(), 1);
+        assert_eq!(compiled.warnings[0].message, "Unreachable code.");
+        assert_eq!(compiled.warnings[0].severity, Severity::Warning);
+
+        let options = CompileOptions {
+            werror: true,
+            ..Default::default()
+        };
+        match super::compile(source, &options, &mut HashMap::new()) {
+            Err(InterpretError::CompileError {
+                diagnostics,
+                category,
+            }) => {
+                let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+                assert_eq!(
+                    messages,
+                    ["Unreachable code.", "1 warning(s) treated as errors."]
+                );
+                assert!(category.is_none());
+            }
+            _ => panic!("expected the warning to fail the compile"),
+        }
+    }
+
+    #[test]
+    fn only_errors_show_the_source_line() {
+        let written = |diagnostic: &Diagnostic| {
+            let mut text = Vec::new();
+            diagnostic.write_to(&mut text).unwrap();
+            String::from_utf8(text).unwrap()
+        };
+
+        let source = "fun f() { return 1; print 2; }";
+        let compiled = super::compile(source, &CompileOptions::default(), &mut HashMap::new());
+        assert_eq!(
+            written(&compiled.unwrap().warnings[0]),
+            "[line 1] Warning at 'print': Unreachable code.\n"
+        );
+
+        assert_eq!(
+            written(&compile_errors("print (;")[0]),
+            "[line 1] Error at ';': Expect expression.\n    print (;\n           ^\n"
+        );
+    }
+
+    #[test]
+    fn one_var_statement_declares_several_variables() {
+        assert_eq!(
+            output(r#"var a = 1, b, c = "x"; print a; print b; print c;"#),
+            "1\nnil\nx\n"
+        );
+        assert_eq!(
+            output("{ var a = 1, b = a + 1, c = b * 2; print a; print b; print c; }"),
+            "1\n2\n4\n"
+        );
+    }
+
+    #[test]
+    fn else_if_chains_pick_one_branch() {
+        let program = |x: i32| {
+            format!(
+                r#"
+                var x = {x};
+                if (x < 3) print "low";
+                else if (x < 7) print "mid";
+                else print "high";
+                "#
+            )
+        };
+        assert_eq!(output(&program(1)), "low\n");
+        assert_eq!(output(&program(5)), "mid\n");
+        assert_eq!(output(&program(9)), "high\n");
+    }
+
+    #[test]
+    fn print_can_be_called_as_a_function() {
+        let options = CompileOptions {
+            print_as_fn: true,
+            ..Default::default()
+        };
+        let (result, printed) = run_with("print(1); var p = print; p(\"two\");", &options);
+        assert!(result.is_ok());
+        assert_eq!(printed, "1\ntwo\n");
+
+        // Without the option `print` stays a statement.
+        assert_eq!(output("print 1; print(2);"), "1\n2\n");
+    }
+
+    #[test]
+    fn const_globals_cannot_be_assigned_or_redeclared() {
+        let first_error = |source: &str| compile_errors(source)[0].message.clone();
+        assert_eq!(
+            first_error("const x = 1; x = 2;"),
+            "Cannot assign to const 'x'."
+        );
+        assert_eq!(
+            first_error("const x = 1; fun f() { x = 3; }"),
+            "Cannot assign to const 'x'."
+        );
+        assert_eq!(
+            first_error("const x = 1; var x = 2;"),
+            "Cannot redeclare const 'x'."
+        );
+        assert_eq!(
+            first_error("const x = 1; const x = 2;"),
+            "Cannot redeclare const 'x'."
+        );
+        assert_eq!(output("const x = 1; print x;"), "1\n");
+    }
+
+    #[test]
+    fn not_is_logical_negation() {
+        assert_eq!(
+            output("print not nil; print not 0; print !true; print not not \"x\";"),
+            "true\nfalse\nfalse\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn small_constants_use_their_own_opcodes() {
+        let code = ops(&compile("print 1;", &optimized()));
+        assert_eq!(code[0], OpCode::Constant1);
+        assert!(!code.contains(&OpCode::Constant));
+
+        let code = ops(&compile("print 0; print -1; print - -1;", &optimized()));
+        assert_eq!(
+            code[..6],
+            [
+                OpCode::Constant0,
+                OpCode::Print,
+                OpCode::ConstantMinus1,
+                OpCode::Print,
+                OpCode::Constant1,
+                OpCode::Print
+            ]
+        );
+
+        // They need no optimization pass.
+        let code = ops(&compile("print 1;", &CompileOptions::default()));
+        assert_eq!(code[0], OpCode::Constant1);
+    }
+
+    #[test]
+    fn negated_literals_fold_without_leaving_constants_behind() {
+        let function = compile("print -2.5;", &optimized());
+        assert_eq!(ops(&function)[..2], [OpCode::Constant, OpCode::Print]);
+        assert_eq!(function.chunk.constants.values, [Value::Number(-2.5)]);
+        let (result, printed) = run_with("print -1; print - -1; print -2.5;", &optimized());
+        assert!(result.is_ok());
+        assert_eq!(printed, "-1\n1\n-2.5\n");
+    }
+
+    #[test]
+    fn assignment_in_a_condition_warns() {
+        let warnings = |source| {
+            let compiled = super::compile(source, &CompileOptions::default(), &mut HashMap::new());
+            let warnings = compiled.unwrap().warnings;
+            warnings.into_iter().map(|w| w.message).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            warnings("var x = 1; if (x = 5) print x;"),
+            ["Assignment used as a condition; did you mean '=='?"]
+        );
+        assert_eq!(
+            warnings("var x = 1; while (x = nil) print x;"),
+            ["Assignment used as a condition; did you mean '=='?"]
+        );
+        assert!(warnings("var x = 1; if (x == 5) print x;").is_empty());
+    }
+
+    #[test]
+    fn an_error_on_the_first_token_names_it() {
+        let error = &compile_errors(") print 1;")[0];
+        assert_eq!(error.location, "at ')'");
+        assert_eq!(error.message, "Expect expression.");
+        assert_eq!((error.line, error.column), (1, 1));
+
+        assert_eq!(compile_errors("print")[0].location, "at end");
+    }
+
+    #[test]
+    fn optimizing_shrinks_the_chunk_but_not_the_output() {
+        let source = "{ var a = 1; var b = 2; var c = 3; } print 1 + 2 * 3; print !false;";
+        let naive = compile(source, &CompileOptions::default());
+        let optimized_code = compile(source, &optimized());
+        assert!(
+            optimized_code.chunk.code.len() < naive.chunk.code.len(),
+            "{} < {}",
+            optimized_code.chunk.code.len(),
+            naive.chunk.code.len()
+        );
+        let output_with = |options| run_with(source, &options).1;
+        assert_eq!(output_with(optimized()), "7\ntrue\n");
+        assert_eq!(output_with(CompileOptions::default()), "7\ntrue\n");
+    }
+
+    #[test]
+    fn loops_over_declared_globals_index_their_slots() {
+        let source = "
+            var total = 0;
+            var i = 0;
+            while (i < 1000) { total = total + i; i = i + 1; }
+            fun later() { return undeclared; }
+            var undeclared = total;
+            print later();
+        ";
+        let script = compile(source, &CompileOptions::default());
+        let code = ops(&script);
+        let count = |op: OpCode| code.iter().filter(|o| **o == op).count();
+        assert_eq!(count(OpCode::GetGlobalFast), 6);
+        assert_eq!(count(OpCode::SetGlobalFast), 2);
+        assert_eq!(count(OpCode::GetGlobal), 0);
+
+        // Only the forward reference inside `later` is looked up by name.
+        let later = script
+            .chunk
+            .constants
+            .values
+            .iter()
+            .find_map(|value| match value {
+                Value::Function(function) => Some(ops(function)),
+                _ => None,
+            });
+        assert_eq!(
+            later.unwrap(),
+            [
+                OpCode::GetGlobal,
+                OpCode::Return,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+        assert_eq!(output(source), "499500\n");
+    }
+
+    #[test]
+    fn unread_locals_warn() {
+        let source = "{ var used = 1; var unused = 2; var _ = 3; print used; }";
+        let compiled = super::compile(source, &CompileOptions::default(), &mut HashMap::new());
+        let warnings = compiled.unwrap().warnings;
+        let messages: Vec<_> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(messages, ["Unused variable 'unused'."]);
+        assert_eq!(warnings[0].location, "at 'unused'");
+
+        let options = CompileOptions {
+            werror: true,
+            ..Default::default()
+        };
+        assert!(super::compile(source, &options, &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn pipes_call_the_right_side_with_the_left() {
+        assert_eq!(
+            output(r#"print "  hi  " |> trim |> to_upper; print [1, 2] |> reverse;"#),
+            "HI\n[2, 1]\n"
+        );
+        assert_eq!(runtime_error("print 1 |> 2;"), "Can only call functions.");
+    }
+
+    #[test]
+    fn negated_literals_fold_to_one_opcode() {
+        for (source, literal) in [
+            ("print !true;", OpCode::False),
+            ("print !false;", OpCode::True),
+            ("print !nil;", OpCode::True),
+        ] {
+            let code = ops(&compile(source, &optimized()));
+            assert_eq!(code[..2], [literal, OpCode::Print], "{source}");
+            let code = ops(&compile(source, &CompileOptions::default()));
+            assert_eq!(code[1], OpCode::Not, "{source}");
         }
+        let code = ops(&compile("var x = true; print !x;", &optimized()));
+        assert!(code.contains(&OpCode::Not));
+    }
 
-        Ok(parser.chunk)
+    #[test]
+    fn in_checks_a_half_open_range() {
+        assert_eq!(
+            output("print 5 in 1..10; print 10 in 1..10; print 1 in 1..10; print 0 in 1..10;"),
+            "true\nfalse\ntrue\nfalse\n"
+        );
+        assert_eq!(
+            output("var calls = 0; fun x() { calls = calls + 1; return 3; } print x() in 1..5; print calls;"),
+            "true\n1\n"
+        );
+        assert_eq!(
+            runtime_error(r#"print 1 in "a"..2;"#),
+            "Range bounds must be numbers."
+        );
+    }
+
+    #[test]
+    fn compound_assignment_to_an_element_evaluates_the_index_once() {
+        assert_eq!(
+            output(
+                r#"
+                var calls = 0;
+                fun f() { calls = calls + 1; return 1; }
+                var a = [10, 20];
+                a[f()] += 1;
+                print a;
+                print calls;
+                var m = {"k": 3};
+                m["k"] *= 2;
+                print m;
+                "#
+            ),
+            "[10, 21]\n1\n{k: 6}\n"
+        );
+    }
+
+    #[test]
+    fn every_error_in_a_script_is_collected() {
+        let errors = compile_errors("print (;\nvar 1;\nprint 2");
+        let found: Vec<_> = errors
+            .iter()
+            .map(|d| (d.line, d.column, d.message.as_str(), d.severity))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, 8, "Expect expression.", Severity::Error),
+                (2, 5, "Expect a variable name.", Severity::Error),
+                (3, 8, "Expect ';' after value.", Severity::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_over_huge_bodies_are_compile_errors() {
+        let body = "print x;\n".repeat(25_000);
+        let messages = |source: String| {
+            let errors = compile_errors(&source);
+            errors.into_iter().map(|d| d.message).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(format!("var x; while (x) {{ {body} }}")),
+            ["Loop body too large."]
+        );
+        assert_eq!(
+            messages(format!("var x; if (x) {{ {body} }}")),
+            ["Too much code to jump over."]
+        );
+    }
+
+    #[test]
+    fn global_assigns_past_a_shadowing_local() {
+        assert_eq!(
+            output("var x = 1; fun f() { var x = 2; global x = x + 10; print x; } f(); print x;"),
+            "2\n12\n"
+        );
+        assert_eq!(
+            output("fun f() { global y = 3; } var y; f(); print y;"),
+            "3\n"
+        );
     }
 }
@@ -1,33 +1,77 @@
+mod bytecode;
 mod chunk;
 mod compiler;
+mod natives;
 mod scanner;
+#[cfg(test)]
+mod testing;
 mod value;
 mod vm;
 
-use std::{fs, ops::Add};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    rc::Rc,
+    time::Instant,
+};
 
+use chunk::{Chunk, OpCode};
+use compiler::Diagnostic;
 use qsv_docopt::Docopt;
-use rprompt::prompt_reply;
+use rustyline::DefaultEditor;
 use serde::Deserialize;
+use value::{Function, Value};
 
 const USAGE: &str = "
 Thorium virtual machine.
 
 Usage:
-    thorium
-    thorium <path>
+    thorium [--transcript=<out>] [--repl-history=<path>]
+    thorium [-O] [--time] [--step] [--werror] [--parse-trace] [--print-as-fn] [--max-steps=<n>] [--dump-stack] [--trace-log=<path>] [--emit-json-diagnostics] [--check | --stream | --emit=<out>] <path>
     thorium (-h | --help)
     thorium --version
 
 Options:
-    -h --help     Show this screen.
-    --version     Show version.
+    -h --help                Show this screen.
+    --version                Show version.
+    -O --optimize            Run the bytecode optimization passes.
+    --time                   Report compile and run durations.
+    --step                   Pause before each instruction.
+    --werror                 Treat compiler warnings as errors.
+    --parse-trace            Print how the parser groups each expression.
+    --print-as-fn            Treat print as a builtin function instead of a statement.
+    --max-steps=<n>          Stop with a runtime error after executing n instructions.
+    --dump-stack             Print what is left on the stack after running.
+    --trace-log=<path>       Write each executed instruction and the stack to <path>.
+    --emit-json-diagnostics  Print errors and warnings to stdout as JSON.
+    --check                  Compile the file and report errors without running it.
+    --stream                 Compile and run the file one declaration at a time.
+    --emit=<out>             Write the compiled bytecode to <out> instead of running.
+    --transcript=<out>       Record each REPL line and its output to <out>.
+    --repl-history=<path>    Keep REPL line history in <path> across sessions.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     arg_path: String,
     flag_version: bool,
+    flag_optimize: bool,
+    flag_time: bool,
+    flag_step: bool,
+    flag_werror: bool,
+    flag_parse_trace: bool,
+    flag_print_as_fn: bool,
+    flag_max_steps: Option<u64>,
+    flag_dump_stack: bool,
+    flag_trace_log: Option<String>,
+    flag_emit_json_diagnostics: bool,
+    flag_check: bool,
+    flag_stream: bool,
+    flag_emit: Option<String>,
+    flag_transcript: Option<String>,
+    flag_repl_history: Option<String>,
 }
 
 fn main() {
@@ -35,36 +79,365 @@ fn main() {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    if cfg!(debug_assertions) {
+        vm::install_panic_hook();
+    }
+
     if args.flag_version {
-        println!("{}", env!("CARGO_PKG_VERSION"));
+        print_version();
         return;
     }
 
     if args.arg_path.is_empty() {
-        repl();
+        repl(&args);
+    } else {
+        run_file(&args);
+    }
+}
+
+// Prints the version with the build profile and, when known, the git commit.
+fn print_version() {
+    let profile = if cfg!(debug_assertions) {
+        "debug"
     } else {
-        run_file(&args.arg_path);
+        "release"
+    };
+    match option_env!("THORIUM_GIT_HASH") {
+        Some(hash) => println!("{} ({hash}, {profile})", env!("CARGO_PKG_VERSION")),
+        None => println!("{} ({profile})", env!("CARGO_PKG_VERSION")),
     }
 }
 
-fn repl() {
-    loop {
-        let line = prompt_reply("> ").unwrap();
+// Sends output to the terminal and copies it into the transcript, leaving out
+// the escape codes that color it.
+struct Tee<W> {
+    console: W,
+    transcript: Rc<RefCell<BufWriter<File>>>,
+    in_escape: bool,
+}
+
+impl<W: Write> Tee<W> {
+    fn new(console: W, transcript: Rc<RefCell<BufWriter<File>>>) -> Self {
+        Tee {
+            console,
+            transcript,
+            in_escape: false,
+        }
+    }
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.console.write_all(buf)?;
+        let mut plain = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            if self.in_escape {
+                self.in_escape = !byte.is_ascii_alphabetic();
+            } else if byte == 0x1b {
+                self.in_escape = true;
+            } else {
+                plain.push(byte);
+            }
+        }
+        self.transcript.borrow_mut().write_all(&plain)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.console.flush()?;
+        self.transcript.borrow_mut().flush()
+    }
+}
+
+// Runs each line on the same VM so globals carry over. Lines starting with
+// `:` are REPL commands and never reach the compiler.
+fn repl(args: &Args) {
+    let mut vm = vm::Vm::init();
+    let mut editor = DefaultEditor::new().expect("Failed to start line editor");
+    if let Some(path) = &args.flag_repl_history {
+        // There is nothing to load before the first session.
+        let _ = editor.load_history(path);
+    }
+    let transcript = args.flag_transcript.as_ref().map(|path| {
+        let file = File::create(path).expect("Failed to create transcript");
+        Rc::new(RefCell::new(BufWriter::new(file)))
+    });
+    // Everything the session shows goes through these, so the transcript gets
+    // command output, diagnostics and errors as well as what scripts print.
+    let (mut out, mut err): (Box<dyn Write>, Box<dyn Write>) = match &transcript {
+        Some(transcript) => {
+            vm.set_output(Box::new(Tee::new(io::stdout(), transcript.clone())));
+            vm.set_error_output(Box::new(Tee::new(io::stderr(), transcript.clone())));
+            (
+                Box::new(Tee::new(io::stdout(), transcript.clone())),
+                Box::new(Tee::new(io::stderr(), transcript.clone())),
+            )
+        }
+        None => (Box::new(io::stdout()), Box::new(io::stderr())),
+    };
+    let mut last: Option<Rc<Function>> = None;
+    // Every line that ran, merged into one chunk for `:dump all`.
+    let mut session = Chunk::init();
+    // End of input or an interrupt ends the session like an empty line.
+    while let Ok(line) = editor.readline("> ") {
         if line.is_empty() {
             break;
         };
-        let line = line.add("\0");
-        let _ = vm::interpret(line);
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(path) = &args.flag_repl_history {
+            // Saved as it goes so the history survives an `exit()`.
+            editor.save_history(path).expect("Failed to write history");
+        }
+        if let Some(transcript) = &transcript {
+            writeln!(transcript.borrow_mut(), "> {line}").expect("Failed to write transcript");
+        }
+
+        if let Some(command) = line.strip_prefix(':') {
+            let written = match command.trim() {
+                "dump" => match &last {
+                    Some(function) => function.chunk.write_disassembly("code", &mut out),
+                    None => writeln!(out, "Nothing has been run yet."),
+                },
+                "dump all" => session.write_disassembly("session", &mut out),
+                "globals" => write_globals(&vm, &mut out),
+                "reset" => {
+                    vm.reset();
+                    last = None;
+                    session = Chunk::init();
+                    Ok(())
+                }
+                "quit" => break,
+                other => writeln!(
+                    out,
+                    "Unknown command ':{other}'. Commands are :dump, :dump all, :globals, :reset and :quit."
+                ),
+            };
+            written.expect("Failed to write command output");
+            continue;
+        }
+
+        let options = compiler::CompileOptions::default();
+        match compiler::compile(line, &options, vm.global_slots_mut()) {
+            Ok(compiled) => {
+                write_diagnostics(&compiled.warnings, &mut err);
+                let function = Rc::new(compiled.function);
+                last = Some(function.clone());
+                vm.load(function.clone());
+                if vm.interpret().is_ok() {
+                    if let Err(message) = append_to_session(&mut session, &function.chunk) {
+                        writeln!(out, "{message}").expect("Failed to write command output");
+                    }
+                }
+            }
+            Err(vm::InterpretError::CompileError { diagnostics, .. }) => {
+                write_diagnostics(&diagnostics, &mut err)
+            }
+            Err(vm::InterpretError::RuntimeError { .. }) => {}
+        }
+    }
+
+    if let Some(transcript) = transcript {
+        transcript
+            .borrow_mut()
+            .flush()
+            .expect("Failed to write transcript");
+    }
+}
+
+// Drops the implicit `Nil, Return` ending the session so far, so the merged
+// chunk runs straight on into the next line. A line that doesn't fit leaves
+// the session as it was.
+fn append_to_session(session: &mut Chunk, chunk: &Chunk) -> Result<(), String> {
+    let ending = [OpCode::Nil.into(), OpCode::Return.into()];
+    let len = session.code.len();
+    let line = session.line_at(len.saturating_sub(1)).unwrap_or(0);
+    if session.code.ends_with(&ending) {
+        session.truncate(len - 2);
+    }
+    let appended = session.append(chunk);
+    if appended.is_err() && session.code.len() < len {
+        ending
+            .into_iter()
+            .for_each(|byte| session.write(byte, line));
+    }
+    appended
+}
+
+fn write_diagnostics(diagnostics: &[Diagnostic], out: &mut dyn Write) {
+    for diagnostic in diagnostics {
+        diagnostic
+            .write_to(out)
+            .expect("Failed to write diagnostic");
     }
 }
 
-fn run_file(arg_path: &str) {
-    let file_contents = fs::read_to_string(arg_path).expect("Failed to read file");
-    let result = vm::interpret(file_contents);
+// Lists the globals defined by the user, leaving out the natives.
+fn write_globals(vm: &vm::Vm, out: &mut dyn Write) -> io::Result<()> {
+    let mut names: Vec<_> = vm
+        .globals()
+        .filter(|(_, value)| !matches!(value, Value::NativeFunction(_)))
+        .collect();
+    names.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, value) in names {
+        writeln!(out, "{name} = {value}")?;
+    }
+    Ok(())
+}
+
+fn run_file(args: &Args) {
+    let result = if args.flag_check {
+        check(args)
+    } else if args.flag_stream {
+        stream(args)
+    } else {
+        let file_contents = fs::read(&args.arg_path).expect("Failed to read file");
+        run(file_contents, args)
+    };
 
     match result {
-        Ok(()) => std::process::exit(0),
-        Err(vm::InterpretError::CompileError) => std::process::exit(65),
-        Err(vm::InterpretError::RuntimeError) => std::process::exit(70),
+        Ok(value) => std::process::exit(exit_code(&value)),
+        Err(vm::InterpretError::CompileError {
+            diagnostics,
+            category,
+        }) => {
+            print_diagnostics(&diagnostics, args);
+            std::process::exit(compile_exit_code(category))
+        }
+        Err(vm::InterpretError::RuntimeError { message, line }) => {
+            // Printed as it happened unless it's wanted as JSON.
+            if args.flag_emit_json_diagnostics {
+                print_diagnostics(&[Diagnostic::error(line, message)], args);
+            }
+            std::process::exit(70)
+        }
     };
 }
+
+// Scan and resolve errors get their own codes; anything else is the usual 65.
+fn compile_exit_code(category: Option<compiler::ErrorCategory>) -> i32 {
+    match category {
+        Some(compiler::ErrorCategory::Scan) => 66,
+        Some(compiler::ErrorCategory::Resolve) => 67,
+        Some(compiler::ErrorCategory::Parse) | None => 65,
+    }
+}
+
+// Integral script results in 0..=255 become the exit code, anything else exits 0.
+fn exit_code(value: &Value) -> i32 {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => *n as i32,
+        Value::Int(n) if (0..=255).contains(n) => *n as i32,
+        _ => 0,
+    }
+}
+
+// Bytecode that can't be loaded or written has no source location to point at.
+fn load_error(message: String) -> vm::InterpretError {
+    vm::InterpretError::CompileError {
+        diagnostics: vec![Diagnostic::error(0, message)],
+        category: None,
+    }
+}
+
+fn compile_options(args: &Args) -> compiler::CompileOptions {
+    compiler::CompileOptions {
+        werror: args.flag_werror,
+        parse_trace: args.flag_parse_trace,
+        print_as_fn: args.flag_print_as_fn,
+        optimize: args.flag_optimize,
+        line_offset: 0,
+    }
+}
+
+// Compiles the file for its diagnostics and throws the code away.
+fn check(args: &Args) -> Result<Value, vm::InterpretError> {
+    let source = fs::read_to_string(&args.arg_path).expect("Failed to read file");
+    compile(source, args, &mut HashMap::new())?;
+    Ok(Value::Nil)
+}
+
+// Runs a source file a declaration at a time instead of reading it all first.
+fn stream(args: &Args) -> Result<Value, vm::InterpretError> {
+    let file = fs::File::open(&args.arg_path).expect("Failed to read file");
+    let mut vm = vm::Vm::init();
+    configure(&mut vm, args);
+    vm::interpret_streaming(
+        &mut vm,
+        io::BufReader::new(file),
+        &compile_options(args),
+        |warnings| print_diagnostics(warnings, args),
+    )
+}
+
+fn configure(vm: &mut vm::Vm, args: &Args) {
+    vm.set_step(args.flag_step);
+    vm.set_max_steps(args.flag_max_steps);
+    vm.set_print_errors(!args.flag_emit_json_diagnostics);
+    if let Some(path) = &args.flag_trace_log {
+        let file = File::create(path).expect("Failed to create trace log");
+        vm.set_trace_log(Some(Box::new(BufWriter::new(file))));
+    }
+}
+
+// Compiles source and prints its warnings. Errors are left to the caller.
+fn compile(
+    source: String,
+    args: &Args,
+    global_slots: &mut HashMap<String, usize>,
+) -> Result<Function, vm::InterpretError> {
+    let compiled = compiler::compile(source, &compile_options(args), global_slots)?;
+    if !compiled.warnings.is_empty() {
+        print_diagnostics(&compiled.warnings, args);
+    }
+    Ok(compiled.function)
+}
+
+// Prints to stderr, or to stdout as a JSON array with `--emit-json-diagnostics`.
+fn print_diagnostics(diagnostics: &[Diagnostic], args: &Args) {
+    if args.flag_emit_json_diagnostics {
+        let json = serde_json::to_string(diagnostics).expect("Failed to serialize diagnostics");
+        println!("{json}");
+    } else {
+        diagnostics.iter().for_each(Diagnostic::print);
+    }
+}
+
+// Runs a source file, or a bytecode file previously written with `--emit`.
+fn run(contents: Vec<u8>, args: &Args) -> Result<Value, vm::InterpretError> {
+    // Created first so the compiler can resolve globals to the VM's slots.
+    let mut vm = vm::Vm::init();
+    let start = Instant::now();
+    let function = if bytecode::is_bytecode(&contents) {
+        bytecode::deserialize(&contents).map_err(load_error)?
+    } else {
+        let source = String::from_utf8_lossy(&contents).into_owned();
+        compile(source, args, vm.global_slots_mut())?
+    };
+    let compiled = start.elapsed();
+
+    if let Some(path) = &args.flag_emit {
+        let bytes = bytecode::serialize(&function).map_err(load_error)?;
+        fs::write(path, bytes).expect("Failed to write bytecode");
+        return Ok(Value::Nil);
+    }
+
+    vm.load(Rc::new(function));
+    configure(&mut vm, args);
+
+    let start = Instant::now();
+    let result = vm.interpret();
+    let ran = start.elapsed();
+
+    if args.flag_dump_stack {
+        eprintln!("stack: {}", vm.stack());
+    }
+
+    if args.flag_time {
+        eprintln!(
+            "compiled in {:.3}ms, ran in {:.3}ms",
+            compiled.as_secs_f64() * 1000.0,
+            ran.as_secs_f64() * 1000.0
+        );
+    }
+    result
+}
@@ -0,0 +1,1007 @@
+use std::cell::Ref;
+
+use crate::{
+    value::{sorted_entries, NativeError, NativeFunction, Value, VARIADIC},
+    vm::{is_falsey, Vm},
+};
+
+// Natives are given global slots in this order, which serialized bytecode
+// depends on; see the note on `OpCode` before changing it.
+pub const NATIVES: &[NativeFunction] = &[
+    NativeFunction {
+        name: "print",
+        arity: 1,
+        function: print,
+    },
+    NativeFunction {
+        name: "starts_with",
+        arity: 2,
+        function: starts_with,
+    },
+    NativeFunction {
+        name: "ends_with",
+        arity: 2,
+        function: ends_with,
+    },
+    NativeFunction {
+        name: "contains",
+        arity: 2,
+        function: contains,
+    },
+    NativeFunction {
+        name: "index_of",
+        arity: 2,
+        function: index_of,
+    },
+    NativeFunction {
+        name: "count",
+        arity: 2,
+        function: count,
+    },
+    NativeFunction {
+        name: "substring",
+        arity: 3,
+        function: substring,
+    },
+    NativeFunction {
+        name: "split",
+        arity: 2,
+        function: split,
+    },
+    NativeFunction {
+        name: "format",
+        arity: VARIADIC,
+        function: format,
+    },
+    NativeFunction {
+        name: "join",
+        arity: 2,
+        function: join,
+    },
+    NativeFunction {
+        name: "push",
+        arity: 2,
+        function: push,
+    },
+    NativeFunction {
+        name: "pop",
+        arity: 1,
+        function: pop,
+    },
+    NativeFunction {
+        name: "head",
+        arity: 1,
+        function: head,
+    },
+    NativeFunction {
+        name: "tail",
+        arity: 1,
+        function: tail,
+    },
+    NativeFunction {
+        name: "reverse",
+        arity: 1,
+        function: reverse,
+    },
+    NativeFunction {
+        name: "clone",
+        arity: 1,
+        function: clone,
+    },
+    NativeFunction {
+        name: "zip",
+        arity: 2,
+        function: zip,
+    },
+    NativeFunction {
+        name: "enumerate",
+        arity: 1,
+        function: enumerate,
+    },
+    NativeFunction {
+        name: "to_upper",
+        arity: 1,
+        function: to_upper,
+    },
+    NativeFunction {
+        name: "to_lower",
+        arity: 1,
+        function: to_lower,
+    },
+    NativeFunction {
+        name: "trim",
+        arity: 1,
+        function: trim,
+    },
+    NativeFunction {
+        name: "env",
+        arity: 1,
+        function: env,
+    },
+    NativeFunction {
+        name: "read_file",
+        arity: 1,
+        function: read_file,
+    },
+    NativeFunction {
+        name: "write_file",
+        arity: 2,
+        function: write_file,
+    },
+    NativeFunction {
+        name: "sleep",
+        arity: 1,
+        function: sleep,
+    },
+    NativeFunction {
+        name: "exit",
+        arity: 1,
+        function: exit,
+    },
+    NativeFunction {
+        name: "random",
+        arity: 0,
+        function: random,
+    },
+    NativeFunction {
+        name: "random_int",
+        arity: 2,
+        function: random_int,
+    },
+    NativeFunction {
+        name: "seed",
+        arity: 1,
+        function: seed,
+    },
+    NativeFunction {
+        name: "builtins",
+        arity: 0,
+        function: builtins,
+    },
+    NativeFunction {
+        name: "error",
+        arity: 1,
+        function: error,
+    },
+    NativeFunction {
+        name: "assert_eq",
+        arity: 2,
+        function: assert_eq,
+    },
+    NativeFunction {
+        name: "int",
+        arity: 1,
+        function: int,
+    },
+    NativeFunction {
+        name: "parse_int",
+        arity: 1,
+        function: parse_int,
+    },
+    NativeFunction {
+        name: "parse_float",
+        arity: 1,
+        function: parse_float,
+    },
+    NativeFunction {
+        name: "keys",
+        arity: 1,
+        function: keys,
+    },
+    NativeFunction {
+        name: "values",
+        arity: 1,
+        function: values,
+    },
+    NativeFunction {
+        name: "map",
+        arity: 2,
+        function: map,
+    },
+    NativeFunction {
+        name: "filter",
+        arity: 2,
+        function: filter,
+    },
+    NativeFunction {
+        name: "reduce",
+        arity: 3,
+        function: reduce,
+    },
+];
+
+// Only reachable with `--print-as-fn`, where `print` is no longer a keyword.
+fn print(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    vm.print(&args[0])
+        .map_err(|e| NativeError::Message(format!("Failed to print: {e}.")))?;
+    Ok(Value::Nil)
+}
+
+fn starts_with(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (s, prefix) = two_strings("starts_with", args)?;
+    Ok(Value::Boolean(s.starts_with(prefix)))
+}
+
+fn ends_with(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (s, suffix) = two_strings("ends_with", args)?;
+    Ok(Value::Boolean(s.ends_with(suffix)))
+}
+
+fn contains(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (s, needle) = two_strings("contains", args)?;
+    Ok(Value::Boolean(s.contains(needle)))
+}
+
+// Strings are searched by substring and give a character index; arrays are
+// searched by element equality. Returns -1 when the needle isn't found.
+fn index_of(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let index = match args {
+        [Value::DynamicString(s), Value::DynamicString(needle)] => s
+            .find(needle.as_str())
+            .map(|byte_index| s[..byte_index].chars().count()),
+        [Value::Array(values), needle] => values.borrow().iter().position(|v| v == needle),
+        _ => {
+            return Err("Arguments to index_of must be two strings or an array and a value.".into())
+        }
+    };
+    Ok(Value::Number(index.map_or(-1.0, |i| i as f32)))
+}
+
+// Substring matches don't overlap, so `count("aaaa", "aa")` is 2.
+fn count(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let count = match args {
+        [Value::DynamicString(_), Value::DynamicString(needle)] if needle.is_empty() => {
+            return Err("Cannot count empty strings.".into())
+        }
+        [Value::DynamicString(s), Value::DynamicString(needle)] => {
+            s.matches(needle.as_str()).count()
+        }
+        [Value::Array(values), needle] => values.borrow().iter().filter(|v| *v == needle).count(),
+        _ => return Err("Arguments to count must be two strings or an array and a value.".into()),
+    };
+    Ok(Value::Number(count as f32))
+}
+
+// Case mapping is Unicode-aware and may change the length, e.g. "ß" becomes "SS".
+fn to_upper(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = string("to_upper", &args[0])?;
+    Ok(Value::DynamicString(s.to_uppercase()))
+}
+
+fn to_lower(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = string("to_lower", &args[0])?;
+    Ok(Value::DynamicString(s.to_lowercase()))
+}
+
+fn trim(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = string("trim", &args[0])?;
+    Ok(Value::DynamicString(s.trim().to_string()))
+}
+
+// Indices count chars rather than bytes, so multi-byte strings slice cleanly.
+fn substring(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = match &args[0] {
+        Value::DynamicString(s) => s,
+        _ => return Err("First argument to substring must be a string.".into()),
+    };
+    let start = index("substring", &args[1])?;
+    let end = index("substring", &args[2])?;
+
+    let length = s.chars().count();
+    if start > end || end > length {
+        return Err(format!(
+            "Substring range {start}..{end} out of bounds for string of length {length}."
+        )
+        .into());
+    }
+
+    Ok(Value::DynamicString(
+        s.chars().skip(start).take(end - start).collect(),
+    ))
+}
+
+// An empty separator splits the string into its characters.
+fn split(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (s, separator) = two_strings("split", args)?;
+    let parts = if separator.is_empty() {
+        s.chars()
+            .map(|c| Value::DynamicString(c.to_string()))
+            .collect()
+    } else {
+        s.split(separator)
+            .map(|part| Value::DynamicString(part.to_string()))
+            .collect()
+    };
+    Ok(Value::array(parts))
+}
+
+// Replaces each `{}` in the format string with the next argument, while `{{`
+// and `}}` stand for literal braces.
+fn format(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let Some((template, values)) = args.split_first() else {
+        return Err("format expects a format string.".into());
+    };
+    let template = string("format", template)?;
+
+    let mut output = String::with_capacity(template.len());
+    let mut placeholders = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                output.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if let Some(value) = values.get(placeholders) {
+                    output.push_str(&value.to_string());
+                }
+                placeholders += 1;
+            }
+            ('{' | '}', _) => return Err("Unmatched brace in format string.".into()),
+            _ => output.push(c),
+        }
+    }
+
+    if placeholders != values.len() {
+        return Err(format!(
+            "Format string has {placeholders} placeholders but got {} arguments.",
+            values.len()
+        )
+        .into());
+    }
+    Ok(Value::DynamicString(output))
+}
+
+fn join(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match args {
+        [Value::Array(values), Value::DynamicString(separator)] => {
+            let parts: Vec<String> = values.borrow().iter().map(|v| v.to_string()).collect();
+            Ok(Value::DynamicString(parts.join(separator)))
+        }
+        _ => Err("Arguments to join must be an array and a string.".into()),
+    }
+}
+
+// Appends to the array in place and returns its new length.
+fn push(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Array(values) => {
+            let mut values = values.borrow_mut();
+            values.push(args[1].clone());
+            Ok(Value::Number(values.len() as f32))
+        }
+        _ => Err("First argument to push must be an array.".into()),
+    }
+}
+
+// Removes and returns the last element, or nil when the array is empty.
+fn pop(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Array(values) => Ok(values.borrow_mut().pop().unwrap_or(Value::Nil)),
+        _ => Err("Argument to pop must be an array.".into()),
+    }
+}
+
+// Returns the first element, or nil when the array is empty.
+fn head(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let values = array("head", &args[0])?;
+    Ok(values.first().cloned().unwrap_or(Value::Nil))
+}
+
+// Returns a new array holding everything after the first element.
+fn tail(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let values = array("tail", &args[0])?;
+    Ok(Value::array(values.iter().skip(1).cloned().collect()))
+}
+
+fn reverse(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let values = array("reverse", &args[0])?;
+    Ok(Value::array(values.iter().rev().cloned().collect()))
+}
+
+fn clone(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(args[0].deep_clone())
+}
+
+// Pairs up elements, stopping at the end of the shorter array.
+fn zip(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let [Value::Array(a), Value::Array(b)] = args else {
+        return Err("Arguments to zip must be arrays.".into());
+    };
+    Ok(Value::array(
+        a.borrow()
+            .iter()
+            .zip(b.borrow().iter())
+            .map(|(x, y)| Value::array(vec![x.clone(), y.clone()]))
+            .collect(),
+    ))
+}
+
+// Returns `[index, element]` pairs.
+fn enumerate(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let values = array("enumerate", &args[0])?;
+    Ok(Value::array(
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Value::array(vec![Value::Number(i as f32), value.clone()]))
+            .collect(),
+    ))
+}
+
+fn env(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::DynamicString(name) => Ok(std::env::var(name)
+            .map(Value::DynamicString)
+            .unwrap_or(Value::Nil)),
+        _ => Err("Argument to env must be a string.".into()),
+    }
+}
+
+// Ends the process with the given status once the script's output is flushed.
+fn exit(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let code = integer("exit", &args[0])?;
+    if !(0..=255).contains(&code) {
+        return Err("Exit code must be between 0 and 255.".into());
+    }
+    vm.flush_output()
+        .map_err(|e| format!("Failed to flush output: {e}."))?;
+    std::process::exit(code as i32)
+}
+
+// Returns nil when the file can't be read, so scripts can check for it.
+fn read_file(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let path = string("read_file", &args[0])?;
+    Ok(std::fs::read_to_string(path)
+        .map(Value::DynamicString)
+        .unwrap_or(Value::Nil))
+}
+
+// Replaces the file's contents, returning whether the write succeeded.
+fn write_file(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (path, contents) = two_strings("write_file", args)?;
+    Ok(Value::Boolean(std::fs::write(path, contents).is_ok()))
+}
+
+// Pauses for the given number of milliseconds.
+fn sleep(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let ms = match &args[0] {
+        Value::Number(n) if n.is_finite() && *n >= 0.0 => *n as f64,
+        Value::Int(n) if *n >= 0 => *n as f64,
+        _ => return Err("Argument to sleep must be a non-negative number.".into()),
+    };
+    let duration = std::time::Duration::try_from_secs_f64(ms / 1000.0)
+        .map_err(|_| NativeError::from("Sleep duration is too long."))?;
+    std::thread::sleep(duration);
+    Ok(Value::Nil)
+}
+
+// Returns a number in [0, 1).
+fn random(vm: &mut Vm, _args: &[Value]) -> Result<Value, NativeError> {
+    // The top 24 bits fill an f32 mantissa exactly, so 1.0 is never reached.
+    Ok(Value::Number(
+        (vm.next_random() >> 40) as f32 / (1u64 << 24) as f32,
+    ))
+}
+
+// Returns an integer between `lo` and `hi`, both included.
+fn random_int(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let lo = integer("random_int", &args[0])?;
+    let hi = integer("random_int", &args[1])?;
+    if lo > hi {
+        return Err(format!("random_int expects lo <= hi but got {lo} and {hi}.").into());
+    }
+
+    let offset = match hi.abs_diff(lo).checked_add(1) {
+        Some(span) => vm.next_random() % span,
+        // The range covers every i64.
+        None => vm.next_random(),
+    };
+    Ok(Value::Number(lo.wrapping_add(offset as i64) as f32))
+}
+
+// Restarts the random sequence so runs with the same seed repeat it.
+fn seed(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    vm.seed_random(integer("seed", &args[0])? as u64);
+    Ok(Value::Nil)
+}
+
+// Lists the names of every native, sorted.
+fn builtins(_vm: &mut Vm, _args: &[Value]) -> Result<Value, NativeError> {
+    let mut names: Vec<&str> = NATIVES.iter().map(|native| native.name).collect();
+    names.sort_unstable();
+    Ok(Value::array(
+        names
+            .into_iter()
+            .map(|name| Value::DynamicString(name.to_string()))
+            .collect(),
+    ))
+}
+
+// Always fails, aborting the script with the argument as the error message.
+fn error(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::DynamicString(message) => Err(message.clone().into()),
+        other => Err(format!("{other}").into()),
+    }
+}
+
+fn assert_eq(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    if args[0] == args[1] {
+        Ok(Value::Nil)
+    } else {
+        Err(format!("Assertion failed: {} != {}", args[0], args[1]).into())
+    }
+}
+
+// Converts a number to an integer, truncating towards zero.
+fn int(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Int(n) => Ok(Value::Int(*n)),
+        Value::Number(n) if n.is_finite() => Ok(Value::Int(*n as i64)),
+        _ => Err("Argument to int must be a finite number.".into()),
+    }
+}
+
+// Text that isn't a number gives nil so scripts can check for bad input.
+fn parse_int(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = string("parse_int", &args[0])?;
+    Ok(s.trim().parse().map_or(Value::Nil, Value::Int))
+}
+
+fn parse_float(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let s = string("parse_float", &args[0])?;
+    Ok(s.trim().parse().map_or(Value::Nil, Value::Number))
+}
+
+// Keys come back sorted, and `values` follows the same order.
+fn keys(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Map(entries) => Ok(Value::array(
+            sorted_entries(&entries.borrow())
+                .into_iter()
+                .map(|(key, _)| Value::DynamicString(key.clone()))
+                .collect(),
+        )),
+        _ => Err("Argument to keys must be a map.".into()),
+    }
+}
+
+fn values(_vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Map(entries) => Ok(Value::array(
+            sorted_entries(&entries.borrow())
+                .into_iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        )),
+        _ => Err("Argument to values must be a map.".into()),
+    }
+}
+
+fn map(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (values, callback) = array_and_callback("map", args)?;
+    let mut mapped = Vec::with_capacity(values.len());
+    for value in values {
+        mapped.push(vm.call_function(callback.clone(), std::slice::from_ref(&value))?);
+    }
+    Ok(Value::array(mapped))
+}
+
+fn filter(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (values, callback) = array_and_callback("filter", args)?;
+    let mut filtered = Vec::new();
+    for value in values {
+        if !is_falsey(&vm.call_function(callback.clone(), std::slice::from_ref(&value))?) {
+            filtered.push(value);
+        }
+    }
+    Ok(Value::array(filtered))
+}
+
+fn reduce(vm: &mut Vm, args: &[Value]) -> Result<Value, NativeError> {
+    let (values, callback) = array_and_callback("reduce", args)?;
+    let mut accumulator = args[2].clone();
+    for value in values {
+        accumulator = vm.call_function(callback.clone(), &[accumulator, value])?;
+    }
+    Ok(accumulator)
+}
+
+// Returns a copy of the array so the callback is free to change the original.
+fn array_and_callback<'a>(
+    name: &str,
+    args: &'a [Value],
+) -> Result<(Vec<Value>, &'a Value), String> {
+    match args {
+        [Value::Array(values), callback @ (Value::Function(_) | Value::NativeFunction(_)), ..] => {
+            Ok((values.borrow().clone(), callback))
+        }
+        _ => Err(format!(
+            "Arguments to {name} must be an array and a function."
+        )),
+    }
+}
+
+fn array<'a>(name: &str, value: &'a Value) -> Result<Ref<'a, Vec<Value>>, String> {
+    match value {
+        Value::Array(values) => Ok(values.borrow()),
+        _ => Err(format!("Argument to {name} must be an array.")),
+    }
+}
+
+fn integer(name: &str, value: &Value) -> Result<i64, String> {
+    value
+        .as_integer()
+        .ok_or_else(|| format!("Arguments to {name} must be integers."))
+}
+
+fn index(name: &str, value: &Value) -> Result<usize, String> {
+    value
+        .as_integer()
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| format!("Index arguments to {name} must be non-negative integers."))
+}
+
+fn string<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    match value {
+        Value::DynamicString(s) => Ok(s),
+        _ => Err(format!("Argument to {name} must be a string.")),
+    }
+}
+
+fn two_strings<'a>(name: &str, args: &'a [Value]) -> Result<(&'a str, &'a str), String> {
+    match args {
+        [Value::DynamicString(a), Value::DynamicString(b)] => Ok((a, b)),
+        _ => Err(format!("Arguments to {name} must be strings.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{output, runtime_error};
+
+    #[test]
+    fn string_search_natives() {
+        assert_eq!(
+            output(
+                r#"
+                print starts_with("thorium", "tho");
+                print starts_with("thorium", "rium");
+                print ends_with("thorium", "rium");
+                print ends_with("thorium", "tho");
+                print contains("thorium", "ori");
+                print contains("thorium", "x");
+                "#
+            ),
+            "true\nfalse\ntrue\nfalse\ntrue\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn empty_needles_are_always_found() {
+        assert_eq!(
+            output(
+                r#"
+                print starts_with("abc", "");
+                print ends_with("abc", "");
+                print contains("abc", "");
+                print contains("", "");
+                "#
+            ),
+            "true\ntrue\ntrue\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn substring_counts_characters() {
+        assert_eq!(
+            output(r#"print substring("héllo", 1, 3); print substring("héllo", 0, 5);"#),
+            "él\nhéllo\n"
+        );
+        assert_eq!(
+            runtime_error(r#"substring("héllo", 2, 6);"#),
+            "Substring range 2..6 out of bounds for string of length 5."
+        );
+    }
+
+    #[test]
+    fn join_undoes_split() {
+        assert_eq!(
+            output(
+                r#"
+                var strings = ["a,b,,c", ",", "", "no separator"];
+                foreach (s in strings) {
+                    print join(split(s, ","), ",") == s;
+                }
+                "#
+            ),
+            "true\ntrue\ntrue\ntrue\n"
+        );
+        assert_eq!(output(r#"print split("ab", "");"#), "[a, b]\n");
+    }
+
+    #[test]
+    fn env_reads_the_environment() {
+        std::env::set_var("THORIUM_TEST_ENV", "from the harness");
+        assert_eq!(
+            output(r#"print env("THORIUM_TEST_ENV"); print env("THORIUM_TEST_UNSET");"#),
+            "from the harness\nnil\n"
+        );
+    }
+
+    #[test]
+    fn map_filter_and_reduce() {
+        assert_eq!(
+            output(
+                r#"
+                fun double(x) { return x * 2; }
+                fun positive(x) { return x > 0; }
+                fun add(a, b) { return a + b; }
+                print map([1, 2, 3], double);
+                print filter([-1, 2, 0, 4], positive);
+                print reduce([1, 2, 3, 4], add, 0);
+                print reduce([], add, 0);
+                "#
+            ),
+            "[2, 4, 6]\n[2, 4]\n10\n0\n"
+        );
+    }
+
+    #[test]
+    fn keys_and_values_come_in_key_order() {
+        assert_eq!(
+            output(
+                r#"
+                var m = {"b": 2, "a": 1, "c": 3};
+                print keys(m);
+                print values(m);
+                print keys({});
+                "#
+            ),
+            "[a, b, c]\n[1, 2, 3]\n[]\n"
+        );
+    }
+
+    #[test]
+    fn sleep_returns_nil() {
+        assert_eq!(output("print sleep(1); print sleep(int(0));"), "nil\nnil\n");
+        assert_eq!(
+            runtime_error("sleep(-1);"),
+            "Argument to sleep must be a non-negative number."
+        );
+        assert_eq!(
+            runtime_error("sleep(1000000000000000000000000.0);"),
+            "Sleep duration is too long."
+        );
+    }
+
+    #[test]
+    fn case_conversion_and_trim_handle_non_ascii() {
+        assert_eq!(
+            output(
+                r#"
+                print to_upper("straße");
+                print to_lower("ÀÉÎ");
+                print "[" + trim("\u{3000} ünï \n") + "]";
+                "#
+            ),
+            "STRASSE\nàéî\n[ünï]\n"
+        );
+    }
+
+    #[test]
+    fn parse_int_and_parse_float_return_nil_on_bad_input() {
+        assert_eq!(
+            output(
+                r#"
+                print parse_int("42");
+                print parse_int("-3");
+                print parse_int(" 7 ");
+                print parse_int("4x");
+                print parse_int("");
+                print parse_float("2.5");
+                print parse_float("	1e3 ");
+                print parse_float("abc");
+                "#
+            ),
+            "42\n-3\n7\nnil\nnil\n2.5\n1000\nnil\n"
+        );
+        assert_eq!(
+            runtime_error("parse_int(5);"),
+            "Argument to parse_int must be a string."
+        );
+        assert_eq!(
+            runtime_error("parse_float(nil);"),
+            "Argument to parse_float must be a string."
+        );
+    }
+
+    #[test]
+    fn push_and_pop_are_seen_through_every_binding() {
+        assert_eq!(
+            output(
+                r#"
+                var a = [1];
+                var b = a;
+                print push(b, 2);
+                print a;
+                print pop(a);
+                print pop(a);
+                print pop(b);
+                print b;
+                "#
+            ),
+            "2\n[1, 2]\n2\n1\nnil\n[]\n"
+        );
+    }
+
+    #[test]
+    fn format_fills_placeholders_in_order() {
+        assert_eq!(
+            output(
+                r#"
+                print format("{} + {} = {}", 1, 2, 1 + 2);
+                print format("{{{}}}", "x");
+                print format("none");
+                "#
+            ),
+            "1 + 2 = 3\n{x}\nnone\n"
+        );
+        assert_eq!(
+            runtime_error(r#"format("{}{}", "a");"#),
+            "Format string has 2 placeholders but got 1 arguments."
+        );
+        assert_eq!(
+            runtime_error(r#"format("{}", 1, 2);"#),
+            "Format string has 1 placeholders but got 2 arguments."
+        );
+    }
+
+    #[test]
+    fn seeded_random_numbers_repeat() {
+        let source = r#"
+            seed(42);
+            var first = [random(), random_int(1, 6), random_int(1, 6), random()];
+            seed(42);
+            var second = [random(), random_int(1, 6), random_int(1, 6), random()];
+            print first == second;
+            print random() < 1;
+            print random_int(3, 3);
+        "#;
+        assert_eq!(output(source), "true\ntrue\n3\n");
+        assert_eq!(
+            runtime_error("random_int(3, 1);"),
+            "random_int expects lo <= hi but got 3 and 1."
+        );
+    }
+
+    #[test]
+    fn builtins_lists_the_natives_in_order() {
+        let printed = output("print builtins();");
+        let names: Vec<&str> = printed
+            .trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .split(", ")
+            .collect();
+        for known in ["print", "builtins", "to_upper", "push"] {
+            assert!(names.contains(&known), "{known} in {printed}");
+        }
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]), "{printed}");
+    }
+
+    #[test]
+    fn head_tail_and_reverse_leave_the_input_alone() {
+        assert_eq!(
+            output(
+                r#"
+                var a = [1, 2, 3];
+                print head(a);
+                print tail(a);
+                print reverse(a);
+                print a;
+                print head([]);
+                print tail([]);
+                print reverse([]);
+                "#
+            ),
+            "1\n[2, 3]\n[3, 2, 1]\n[1, 2, 3]\nnil\n[]\n[]\n"
+        );
+        for native in ["head", "tail", "reverse"] {
+            assert_eq!(
+                runtime_error(&format!("{native}(1);")),
+                format!("Argument to {native} must be an array.")
+            );
+        }
+    }
+
+    #[test]
+    fn index_of_and_count_search_strings_and_arrays() {
+        assert_eq!(
+            output(
+                r#"
+                print index_of("héllo", "l");
+                print index_of("abc", "z");
+                print index_of([1, 2, 3, 2], 2);
+                print index_of([], 1);
+                print count("aaaa", "aa");
+                print count("abc", "z");
+                print count([1, 2, 2], 2);
+                print count([[1], 1], [1]);
+                "#
+            ),
+            "2\n-1\n1\n-1\n2\n0\n2\n1\n"
+        );
+        assert_eq!(
+            runtime_error(r#"count("abc", "");"#),
+            "Cannot count empty strings."
+        );
+    }
+
+    #[test]
+    fn scripts_read_back_files_they_write() {
+        let path = std::env::temp_dir().join(format!("thorium-natives-{}.txt", std::process::id()));
+        let path = path.display().to_string();
+        let printed = output(&format!(
+            r#"
+            print write_file("{path}", "héllo\n");
+            print read_file("{path}") == "héllo\n";
+            print read_file("{path}/missing");
+            print write_file("{path}/missing", "x");
+            "#
+        ));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(printed, "true\ntrue\nnil\nfalse\n");
+        assert_eq!(
+            runtime_error("read_file(1);"),
+            "Argument to read_file must be a string."
+        );
+    }
+
+    #[test]
+    fn zip_stops_at_the_shorter_array() {
+        assert_eq!(
+            output(
+                r#"
+                print zip([1, 2], ["a", "b"]);
+                print zip([1, 2, 3], ["a"]);
+                print zip([], [1]);
+                print enumerate(["a", "b"]);
+                print enumerate([]);
+                "#
+            ),
+            "[[1, a], [2, b]]\n[[1, a]]\n[]\n[[0, a], [1, b]]\n[]\n"
+        );
+        assert_eq!(
+            runtime_error("zip(1, []);"),
+            "Arguments to zip must be arrays."
+        );
+        assert_eq!(
+            runtime_error(r#"enumerate("a");"#),
+            "Argument to enumerate must be an array."
+        );
+    }
+
+    #[test]
+    fn clones_are_deep_but_assignment_shares() {
+        assert_eq!(
+            output(
+                r#"
+                var a = [[1], {"k": [2]}];
+                var copy = clone(a);
+                var shared = a;
+                push(copy[0], 9);
+                copy[1]["k"][0] = 7;
+                push(shared[0], 8);
+                print a;
+                print copy;
+                print clone(3);
+                "#
+            ),
+            "[[1, 8], {k: [2]}]\n[[1, 9], {k: [7]}]\n3\n"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use arcstr::{ArcStr, Substr};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
@@ -6,6 +8,15 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    column: usize,
+    // Where the token `column` was counted for starts.
+    column_start: usize,
+    keywords: HashMap<&'static str, TokenType>,
+    // Set once `Eof` has been handed out, after which iteration stops.
+    finished: bool,
+    // Line number of the first line of `source`.
+    first_line: usize,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, TryFromPrimitive, IntoPrimitive, Hash)]
@@ -16,11 +27,14 @@ pub enum TokenType {
     Rightparen,
     Leftbrace,
     Rightbrace,
+    Leftbracket,
+    Rightbracket,
     Comma,
     Dot,
     Minus,
     Plus,
     Semicolon,
+    Colon,
     Slash,
     Star,
     // One or two character tokens.
@@ -32,19 +46,32 @@ pub enum TokenType {
     Greaterequal,
     Less,
     Lessequal,
+    DotDot,
+    PipeGreater,
+    Plusequal,
+    Minusequal,
+    Starequal,
+    Slashequal,
     // Literals.
     Identifier,
     String,
+    Bytes,
     Number,
     // Keywords.
     And,
     Class,
+    Const,
+    Do,
     Else,
     False,
     For,
+    Foreach,
     Fun,
+    Global,
     If,
+    In,
     Nil,
+    Not,
     Or,
     Print,
     Return,
@@ -62,40 +89,85 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: Substr,
     pub line: usize,
+    pub column: usize,
 }
 
 impl Token {
-    pub fn make_token(tt: TokenType, lexeme: &str, line: usize) -> Self {
+    pub fn make_token(tt: TokenType, lexeme: &str, line: usize, column: usize) -> Self {
         Token {
             token_type: tt,
             lexeme: Substr::from(lexeme),
             line,
+            column,
         }
     }
 
-    pub fn make_error_token(error: &str, line: usize) -> Self {
+    pub fn make_error_token(error: &str, line: usize, column: usize) -> Self {
         Token {
             token_type: TokenType::Error,
             lexeme: Substr::from(error),
             line,
+            column,
         }
     }
 }
 
 impl Scanner {
-    pub fn init(source: String) -> Self {
+    pub fn init(source: impl Into<ArcStr>) -> Self {
         Scanner {
-            source: ArcStr::from(source),
+            source: source.into(),
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
+            column_start: 0,
+            finished: false,
+            first_line: 1,
+            keywords: HashMap::from([
+                ("and", TokenType::And),
+                ("class", TokenType::Class),
+                ("const", TokenType::Const),
+                ("do", TokenType::Do),
+                ("else", TokenType::Else),
+                ("false", TokenType::False),
+                ("for", TokenType::For),
+                ("foreach", TokenType::Foreach),
+                ("fun", TokenType::Fun),
+                ("global", TokenType::Global),
+                ("if", TokenType::If),
+                ("in", TokenType::In),
+                ("nil", TokenType::Nil),
+                ("not", TokenType::Not),
+                ("or", TokenType::Or),
+                ("print", TokenType::Print),
+                ("return", TokenType::Return),
+                ("super", TokenType::Super),
+                ("this", TokenType::This),
+                ("true", TokenType::True),
+                ("var", TokenType::Var),
+                ("while", TokenType::While),
+            ]),
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    fn scan_token(&mut self) -> Token {
         self.skip_whitespace_and_comments();
 
         self.start = self.current;
+        // Count chars rather than bytes, skipping UTF-8 continuation bytes.
+        // Counting on from the previous token keeps long lines linear.
+        let (from, column) = if self.column_start < self.line_start {
+            (self.line_start, 1)
+        } else {
+            (self.column_start, self.column)
+        };
+        self.column = self.source.as_bytes()[from..self.start]
+            .iter()
+            .filter(|b| (**b & 0xc0) != 0x80)
+            .count()
+            + column;
+        self.column_start = self.start;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -103,7 +175,12 @@ impl Scanner {
 
         let c = self.advance();
 
-        if c.is_alphabetic() {
+        if c == 'b' && self.peek() == '"' {
+            self.advance();
+            return self.parse_string(true);
+        }
+
+        if c.is_alphabetic() || c == '_' {
             return self.parse_identifier();
         }
 
@@ -116,12 +193,21 @@ impl Scanner {
             ')' => self.make_token(TokenType::Rightparen),
             '{' => self.make_token(TokenType::Leftbrace),
             '}' => self.make_token(TokenType::Rightbrace),
+            '[' => self.make_token(TokenType::Leftbracket),
+            ']' => self.make_token(TokenType::Rightbracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
             ',' => self.make_token(TokenType::Comma),
+            '.' if self.peek().is_ascii_digit() => self.parse_number(),
+            '.' if self.match_char('.') => self.make_token(TokenType::DotDot),
             '.' => self.make_token(TokenType::Dot),
+            '-' if self.match_char('=') => self.make_token(TokenType::Minusequal),
             '-' => self.make_token(TokenType::Minus),
+            '+' if self.match_char('=') => self.make_token(TokenType::Plusequal),
             '+' => self.make_token(TokenType::Plus),
+            '/' if self.match_char('=') => self.make_token(TokenType::Slashequal),
             '/' => self.make_token(TokenType::Slash),
+            '*' if self.match_char('=') => self.make_token(TokenType::Starequal),
             '*' => self.make_token(TokenType::Star),
 
             '!' => {
@@ -151,6 +237,8 @@ impl Scanner {
                 })
             }
 
+            '|' if self.match_char('>') => self.make_token(TokenType::PipeGreater),
+
             '>' => {
                 let matched = self.match_char('=');
                 self.make_token(if matched {
@@ -160,16 +248,15 @@ impl Scanner {
                 })
             }
 
-            '"' => self.parse_string(),
-            '\0' => self.make_token(TokenType::Eof),
+            '"' => self.parse_string(false),
 
-            _ => Token::make_error_token("Unexpected character.", self.line),
+            _ => Token::make_error_token("Unexpected character.", self.line, self.column),
         }
     }
 
     fn make_token(&mut self, t: TokenType) -> Token {
         let lexeme = &self.source[self.start..self.current];
-        Token::make_token(t, lexeme, self.line)
+        Token::make_token(t, lexeme, self.line, self.column)
     }
 
     fn is_at_end(&self) -> bool {
@@ -200,19 +287,16 @@ impl Scanner {
         loop {
             let c = self.peek();
             match c {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                '\n' => {
-                    self.line += 1;
+                ' ' | '\t' => {
                     self.advance();
                 }
+                '\r' | '\n' => self.newline(),
                 '/' => {
                     if self.is_at_end() {
                         return;
                     }
                     if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
+                        while !matches!(self.peek(), '\r' | '\n') && !self.is_at_end() {
                             self.advance();
                         }
                     } else {
@@ -224,6 +308,45 @@ impl Scanner {
         }
     }
 
+    // Consumes a line break, counting `\r\n`, `\n` and a lone `\r` as one line each.
+    fn newline(&mut self) {
+        if self.advance() == '\r' && self.peek() == '\n' {
+            self.advance();
+        }
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    // Numbers lines from `line` instead of 1, for source that continues an
+    // earlier piece of the same file.
+    pub fn start_at_line(&mut self, line: usize) {
+        self.line = line;
+        self.first_line = line;
+    }
+
+    // Returns the text of a source line, splitting lines the same way the
+    // scanner counts them.
+    pub fn source_line(&self, line: usize) -> Option<&str> {
+        let bytes = self.source.as_bytes();
+        let is_line_break = |b: &u8| matches!(b, b'\r' | b'\n');
+
+        let mut start = 0;
+        for _ in self.first_line..line {
+            let end = start + bytes[start..].iter().position(is_line_break)?;
+            start = if bytes[end] == b'\r' && bytes.get(end + 1) == Some(&b'\n') {
+                end + 2
+            } else {
+                end + 1
+            };
+        }
+
+        let end = bytes[start..]
+            .iter()
+            .position(is_line_break)
+            .map_or(bytes.len(), |length| start + length);
+        Some(&self.source[start..end])
+    }
+
     fn peek(&self) -> char {
         if self.is_at_end() {
             return '\0';
@@ -232,48 +355,80 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
+        match self.source.as_bytes().get(self.current + 1) {
+            Some(byte) => *byte as char,
+            None => '\0',
         }
-        self.source.as_bytes()[self.current + 1] as char
     }
 
-    fn parse_string(&mut self) -> Token {
+    fn parse_string(&mut self, bytes: bool) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            match self.peek() {
+                '\r' | '\n' => self.newline(),
+                '\\' => {
+                    self.advance();
+                    if !matches!(self.peek(), '\r' | '\n') && !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                _ => {
+                    self.advance();
+                }
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            Token::make_error_token("Unterminated string.", self.line)
+            return Token::make_error_token("Unterminated string.", self.line, self.column);
+        }
+
+        self.advance();
+        let (prefix, token_type) = if bytes {
+            (2, TokenType::Bytes)
         } else {
-            self.advance();
-            self.make_token(TokenType::String)
+            (1, TokenType::String)
+        };
+
+        let contents = &self.source[self.start + prefix..self.current - 1];
+        match unescape(contents, bytes) {
+            Ok(_) => self.make_token(token_type),
+            Err(message) => Token::make_error_token(&message, self.line, self.column),
         }
     }
 
+    // Numbers may start with a dot (`.5`) but not end with one: `5.` is an
+    // error, while `5.x` still scans as a property access on `5`.
     fn parse_number(&mut self) -> Token {
-        while self.peek().is_ascii_digit() {
-            self.advance();
+        self.skip_digits();
+
+        let has_fraction = self.source[self.start..self.current].starts_with('.');
+        if self.peek() == '.' && !has_fraction {
+            let next = self.peek_next();
+            if next.is_ascii_digit() {
+                self.advance();
+                self.skip_digits();
+            } else if !(next.is_alphabetic() || next == '_' || next == '.') {
+                self.advance();
+                return Token::make_error_token(
+                    "Expect digit after '.' in number.",
+                    self.line,
+                    self.column,
+                );
+            }
         }
 
-        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
-            self.advance();
-        }
+        self.make_token(TokenType::Number)
+    }
 
+    fn skip_digits(&mut self) {
         while self.peek().is_ascii_digit() {
             self.advance();
         }
-
-        self.make_token(TokenType::Number)
     }
 
     fn parse_identifier(&mut self) -> Token {
         loop {
             let c = self.peek();
-            if c.is_alphanumeric() {
+            if c.is_alphanumeric() || c == '_' {
                 let _ = self.advance();
             } else {
                 break;
@@ -284,63 +439,249 @@ impl Scanner {
         self.make_token(token_type)
     }
 
+    // Lets a keyword be scanned as a plain identifier from now on.
+    pub fn remove_keyword(&mut self, keyword: &str) {
+        self.keywords.remove(keyword);
+    }
+
     fn get_identifier_type(&self) -> TokenType {
-        let c = self.source.as_bytes()[self.start] as char;
+        let lexeme = &self.source[self.start..self.current];
+        self.keywords
+            .get(lexeme)
+            .cloned()
+            .unwrap_or(TokenType::Identifier)
+    }
+}
 
-        match c {
-            'a' => self.check_keyword(1, "nd", TokenType::And),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, "lse", TokenType::Else),
-            'f' => {
-                if (self.current - self.start) > 1 {
-                    let c = self.source.as_bytes()[self.start + 1] as char;
-                    match c {
-                        'a' => self.check_keyword(2, "lse", TokenType::False),
-                        'o' => self.check_keyword(2, "r", TokenType::For),
-                        'u' => self.check_keyword(2, "n", TokenType::Fun),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
+// Yields every token in the source, ending with exactly one `Eof`.
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
+
+        let token = self.scan_token();
+        self.finished = token.token_type == TokenType::Eof;
+        Some(token)
+    }
+}
+
+// Resolves the escape sequences of a string literal's contents. `\xNN` is
+// limited to ASCII in strings so they stay valid UTF-8, while byte strings
+// accept any byte but no `\u{...}` escapes.
+pub fn unescape(contents: &str, bytes: bool) -> Result<Vec<u8>, String> {
+    let invalid = |sequence: &str| format!("Invalid escape sequence '\\{sequence}'.");
+    let mut unescaped = Vec::with_capacity(contents.len());
+    let mut chars = contents.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            unescaped.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push(b'\n'),
+            Some('t') => unescaped.push(b'\t'),
+            Some('r') => unescaped.push(b'\r'),
+            Some('0') => unescaped.push(b'\0'),
+            Some('\\') => unescaped.push(b'\\'),
+            Some('"') => unescaped.push(b'"'),
+            // Strings have no interpolation, so `$` is always literal; `\$`
+            // lets `\${` be written ahead of `${...}` gaining a meaning.
+            Some('$') => unescaped.push(b'$'),
+            Some('x') => {
+                let digits: String = chars.clone().take(2).collect();
+                let byte = parse_hex(&digits, 2)
+                    .filter(|byte| digits.len() == 2 && (bytes || *byte <= 0x7f))
+                    .ok_or_else(|| invalid(&format!("x{digits}")))?;
+                chars.nth(1);
+                unescaped.push(byte as u8);
             }
-            'i' => self.check_keyword(1, "f", TokenType::If),
-            'n' => self.check_keyword(1, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, "uper", TokenType::Super),
-            't' => {
-                if (self.current - self.start) > 1 {
-                    let c = self.source.as_bytes()[self.start + 1] as char;
-                    match c {
-                        'h' => self.check_keyword(2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, "ue", TokenType::True),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
+            Some('u') if !bytes => {
+                let rest = chars.as_str();
+                let end = rest.find('}').filter(|_| rest.starts_with('{'));
+                let sequence = &rest[..end.map_or(0, |end| end + 1)];
+                let c = end
+                    .and_then(|end| parse_hex(&rest[1..end], 6))
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| invalid(&format!("u{sequence}")))?;
+
+                let mut buffer = [0; 4];
+                unescaped.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                chars = rest[sequence.len()..].chars();
+            }
+            Some(c) => return Err(invalid(&c.to_string())),
+            None => return Err(invalid("")),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+fn parse_hex(digits: &str, max_digits: usize) -> Option<u32> {
+    if digits.is_empty() || digits.len() > max_digits {
+        return None;
+    }
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifier_lines(source: &str) -> Vec<(String, usize)> {
+        Scanner::init(source)
+            .filter(|token| token.token_type == TokenType::Identifier)
+            .map(|token| (token.lexeme.to_string(), token.line))
+            .collect()
+    }
+
+    #[test]
+    fn each_line_ending_counts_as_one_line() {
+        let lines = identifier_lines("a\r\nb\nc\rd\n\r\ne");
+        let expected = [("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 6)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, line)| (name.to_string(), *line))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn line_endings_inside_strings_count_the_same() {
+        let lines = identifier_lines("\"1\r\n2\n3\r4\" a // x\r\nb");
+        assert_eq!(lines, [("a".to_string(), 4), ("b".to_string(), 5)]);
+    }
+
+    #[test]
+    fn every_keyword_scans_as_its_token() {
+        let keywords = Scanner::init("").keywords;
+        for (keyword, token_type) in &keywords {
+            let token = Scanner::init(*keyword).next().unwrap();
+            assert_eq!(&token.token_type, token_type, "{keyword}");
+
+            // Words a keyword starts, or that start it, are plain identifiers.
+            let longer = format!("{keyword}s");
+            let shorter = &keyword[..keyword.len() - 1];
+            for word in [longer.as_str(), shorter] {
+                let token = Scanner::init(word.to_string()).next().unwrap();
+                let expected = match keywords.get(word) {
+                    Some(token_type) => token_type.clone(),
+                    None => TokenType::Identifier,
+                };
+                assert_eq!(token.token_type, expected, "{word}");
             }
-            'v' => self.check_keyword(1, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, "hile", TokenType::While),
-            _ => TokenType::Identifier,
         }
     }
 
-    fn check_keyword(&self, start: usize, rest: &str, token_type: TokenType) -> TokenType {
-        let length = rest.len();
-        let substring_start = self.start + start;
+    #[test]
+    fn hex_and_unicode_escapes() {
+        assert_eq!(unescape(r"\x41\u{42}c", false).unwrap(), b"ABc");
+        assert_eq!(unescape(r"\u{1F600}", false).unwrap(), "😀".as_bytes());
+        assert_eq!(
+            unescape(r"\u{10FFFF}", false).unwrap(),
+            "\u{10FFFF}".as_bytes()
+        );
+        // Strings stay UTF-8, so `\x` stops at ASCII there but not in bytes.
+        assert!(unescape(r"\x80", false).is_err());
+        assert_eq!(unescape(r"\x80", true).unwrap(), [0x80]);
+    }
 
-        if substring_start + length >= self.source.len() {
-            return TokenType::Identifier;
+    #[test]
+    fn malformed_escapes_are_errors() {
+        for escape in [
+            r"\x4",
+            r"\xzz",
+            r"\u{}",
+            r"\u{110000}",
+            r"\u{D800}",
+            r"\u41",
+        ] {
+            assert!(unescape(escape, false).is_err(), "{escape}");
         }
+    }
 
-        let to_be_matched = &self.source[substring_start..(substring_start + length)];
-        if ((self.current - self.start) == (start + length)) && to_be_matched == rest {
-            token_type
-        } else {
-            TokenType::Identifier
+    #[test]
+    fn leading_dots_start_numbers_and_trailing_dots_are_errors() {
+        let first = |source: &str| {
+            let token = Scanner::init(source.to_string()).next().unwrap();
+            (token.token_type, token.lexeme.to_string())
+        };
+        assert_eq!(first(".5"), (TokenType::Number, ".5".to_string()));
+        assert_eq!(first("5.5"), (TokenType::Number, "5.5".to_string()));
+        for source in ["5. ", "5."] {
+            assert_eq!(
+                first(source),
+                (
+                    TokenType::Error,
+                    "Expect digit after '.' in number.".to_string()
+                ),
+                "{source:?}"
+            );
         }
+        let types: Vec<_> = Scanner::init("1 /").map(|t| t.token_type).collect();
+        assert_eq!(types, [TokenType::Number, TokenType::Slash, TokenType::Eof]);
+
+        // A dot before a name or another dot still stands on its own.
+        let types: Vec<_> = Scanner::init("5.x 1..2").map(|t| t.token_type).collect();
+        assert_eq!(
+            types,
+            [
+                TokenType::Number,
+                TokenType::Dot,
+                TokenType::Identifier,
+                TokenType::Number,
+                TokenType::DotDot,
+                TokenType::Number,
+                TokenType::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn the_iterator_ends_after_one_eof() {
+        let tokens: Vec<_> = Scanner::init("print x;")
+            .map(|token| (token.token_type, token.lexeme.to_string()))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Print, "print".to_string()),
+                (TokenType::Identifier, "x".to_string()),
+                (TokenType::Semicolon, ";".to_string()),
+                (TokenType::Eof, "".to_string()),
+            ]
+        );
+
+        let mut scanner = Scanner::init("");
+        assert_eq!(scanner.next().map(|t| t.token_type), Some(TokenType::Eof));
+        assert!(scanner.next().is_none());
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    fn dollar_signs_are_literal() {
+        assert_eq!(unescape(r"\${x}", false).unwrap(), b"${x}");
+        assert_eq!(unescape("$5", false).unwrap(), b"$5");
+        assert_eq!(unescape("${x}$", false).unwrap(), b"${x}$");
+        assert_eq!(
+            crate::testing::output(r#"var x = 1; print "\${x}"; print "$5";"#),
+            "${x}\n$5\n"
+        );
+    }
+
+    #[test]
+    fn columns_count_chars_from_the_line_start() {
+        let columns: Vec<_> = Scanner::init("a \"ü\" b\n  c d")
+            .map(|token| (token.line, token.column))
+            .collect();
+        assert_eq!(columns, [(1, 1), (1, 3), (1, 7), (2, 3), (2, 5), (2, 6)]);
     }
 }
@@ -0,0 +1,98 @@
+// Helpers for the unit tests: compile and run a script on a fresh VM and
+// collect what it printed.
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    compiler::{self, CompileOptions, Diagnostic},
+    value::{Function, Value},
+    vm::{InterpretError, Vm},
+};
+
+// A shared buffer standing in for stdout.
+#[derive(Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A VM printing into a capture, with runtime errors returned but not printed.
+pub fn vm() -> (Vm, Capture) {
+    let mut vm = Vm::init();
+    let capture = Capture::default();
+    vm.set_output(Box::new(capture.clone()));
+    vm.set_print_errors(false);
+    (vm, capture)
+}
+
+pub fn run(source: &str) -> (Result<Value, InterpretError>, String) {
+    run_with(source, &CompileOptions::default())
+}
+
+pub fn run_with(source: &str, options: &CompileOptions) -> (Result<Value, InterpretError>, String) {
+    let (mut vm, capture) = vm();
+    let result = compiler::compile(source, options, vm.global_slots_mut()).and_then(|compiled| {
+        vm.load(Rc::new(compiled.function));
+        vm.interpret()
+    });
+    (result, capture.text())
+}
+
+// Runs an already compiled or hand-built script.
+pub fn run_function(function: Function) -> (Result<Value, InterpretError>, String) {
+    let (mut vm, capture) = vm();
+    vm.load(Rc::new(function));
+    let result = vm.interpret();
+    (result, capture.text())
+}
+
+// What a script that must succeed printed.
+pub fn output(source: &str) -> String {
+    let (result, output) = run(source);
+    if let Err(error) = result {
+        panic!("{error}");
+    }
+    output
+}
+
+// The message of the runtime error a script must stop with.
+pub fn runtime_error(source: &str) -> String {
+    match run(source).0 {
+        Err(InterpretError::RuntimeError { message, .. }) => message,
+        Err(error) => panic!("expected a runtime error, got {error}"),
+        Ok(value) => panic!("expected a runtime error, got {value}"),
+    }
+}
+
+// Compiled against a fresh VM's globals, so it can run on another fresh VM.
+pub fn compile(source: &str, options: &CompileOptions) -> Function {
+    match compiler::compile(source, options, Vm::init().global_slots_mut()) {
+        Ok(compiled) => compiled.function,
+        Err(error) => panic!("{error}"),
+    }
+}
+
+pub fn compile_errors(source: &str) -> Vec<Diagnostic> {
+    match compiler::compile(source, &CompileOptions::default(), &mut Default::default()) {
+        Err(InterpretError::CompileError { diagnostics, .. }) => diagnostics,
+        Err(error) => panic!("expected a compile error, got {error}"),
+        Ok(_) => panic!("expected a compile error"),
+    }
+}
@@ -1,11 +1,188 @@
-use std::{cmp::Ordering, fmt, ops};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt, ops,
+    rc::Rc,
+};
+
+use crate::{
+    chunk::Chunk,
+    vm::{InterpretError, Vm},
+};
 
 #[derive(Clone, Debug)]
 pub enum Value {
     Boolean(bool),
     Nil,
     Number(f32),
+    Int(i64),
     DynamicString(String),
+    Bytes(Vec<u8>),
+    // Arrays and maps are shared rather than copied on assignment, so changes
+    // made through one binding are seen through all of them. The `clone`
+    // native makes an independent copy.
+    Array(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<HashMap<String, Value>>>),
+    Function(Rc<Function>),
+    NativeFunction(NativeFunction),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+}
+
+impl Value {
+    pub fn array(values: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(values)))
+    }
+
+    pub fn map(entries: HashMap<String, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    // The whole number this value holds, if any: an integer, or a number with
+    // no fractional part. Used wherever a count or an index is expected.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            Value::Number(n) if n.is_finite() && n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    // Copies arrays and maps all the way down; everything else is shared as
+    // usual. Containers reachable more than once, including through a cycle,
+    // are copied once and the copy keeps the same shape.
+    pub fn deep_clone(&self) -> Value {
+        self.deep_clone_with(&mut HashMap::new())
+    }
+
+    fn deep_clone_with(&self, copies: &mut HashMap<*const (), Value>) -> Value {
+        match self {
+            Value::Array(values) => {
+                let key = Rc::as_ptr(values) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                copies.insert(key, Value::Array(copy.clone()));
+                let elements = values.borrow().clone();
+                *copy.borrow_mut() = elements
+                    .iter()
+                    .map(|value| value.deep_clone_with(copies))
+                    .collect();
+                Value::Array(copy)
+            }
+            Value::Map(entries) => {
+                let key = Rc::as_ptr(entries) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+                let copy = Rc::new(RefCell::new(HashMap::new()));
+                copies.insert(key, Value::Map(copy.clone()));
+                let original = entries.borrow().clone();
+                *copy.borrow_mut() = original
+                    .into_iter()
+                    .map(|(key, value)| (key, value.deep_clone_with(copies)))
+                    .collect();
+                Value::Map(copy)
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+// Map entries ordered by key, so anything built from them is the same every run.
+pub fn sorted_entries(entries: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    sorted
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: String,
+}
+
+impl Function {
+    pub fn init(name: &str) -> Self {
+        Function {
+            arity: 0,
+            chunk: Chunk::init(),
+            name: name.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Function>>>,
+}
+
+impl Class {
+    pub fn init(name: &str) -> Self {
+        Class {
+            name: name.to_string(),
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<String, Value>>,
+}
+
+impl Instance {
+    pub fn init(class: Rc<Class>) -> Self {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+// A method looked up on an instance, which becomes `this` when it is called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Function>,
+}
+
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, NativeError>;
+
+#[derive(Debug)]
+pub enum NativeError {
+    // A runtime error the VM reports on behalf of the native.
+    Message(String),
+    // A runtime error raised by a callback that has already been reported.
+    Reported(InterpretError),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::Message(message.to_string())
+    }
+}
+
+// Arity of natives that take any number of arguments and check the count themselves.
+pub const VARIADIC: usize = usize::MAX;
+
+#[derive(Clone, Debug)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
 }
 
 impl fmt::Display for Value {
@@ -13,10 +190,107 @@ impl fmt::Display for Value {
         match self {
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::Int(n) => write!(f, "{}", n),
             Value::DynamicString(s) => write!(f, "{}", s),
+            Value::Bytes(bytes) => {
+                let escaped: String = bytes
+                    .iter()
+                    .flat_map(|b| std::ascii::escape_default(*b))
+                    .map(char::from)
+                    .collect();
+                write!(f, "b\"{}\"", escaped)
+            }
+            Value::Array(_) | Value::Map(_) => write!(f, "{}", self.nested_string(&mut Vec::new())),
+            Value::Function(function) if function.name.is_empty() => write!(f, "<script>"),
+            Value::Function(function) => write!(f, "<fn {}>", function.name),
+            Value::NativeFunction(native) => write!(f, "<native fn {}>", native.name),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::BoundMethod(bound) => write!(f, "<fn {}>", bound.method.name),
+        }
+    }
+}
+
+impl Value {
+    // Arrays and maps that contain themselves print the repeat as `[...]` or
+    // `{...}`; `open` holds the ones being printed further out.
+    fn nested_string(&self, open: &mut Vec<*const ()>) -> String {
+        match self {
+            Value::Array(values) => {
+                let key = Rc::as_ptr(values) as *const ();
+                if open.contains(&key) {
+                    return "[...]".to_string();
+                }
+                open.push(key);
+                let values: Vec<String> = values
+                    .borrow()
+                    .iter()
+                    .map(|v| v.nested_string(open))
+                    .collect();
+                open.pop();
+                format!("[{}]", values.join(", "))
+            }
+            Value::Map(entries) => {
+                let key = Rc::as_ptr(entries) as *const ();
+                if open.contains(&key) {
+                    return "{...}".to_string();
+                }
+                open.push(key);
+                let entries: Vec<String> = sorted_entries(&entries.borrow())
+                    .into_iter()
+                    .map(|(key, value)| format!("{key}: {}", value.nested_string(open)))
+                    .collect();
+                open.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+            other => other.to_string(),
         }
     }
+
+    // Element-wise equality for arrays and maps. A pair already being compared
+    // further out is taken as equal, so cycles end instead of recursing forever.
+    fn nested_eq(&self, other: &Value, comparing: &mut HashSet<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::Array(a), Value::Array(b)) => {
+                let pair = (Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ());
+                if !comparing.insert(pair) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(x, y)| x.nested_eq(y, comparing))
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                let pair = (Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ());
+                if !comparing.insert(pair) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                    && a.iter()
+                        .all(|(key, x)| b.get(key).is_some_and(|y| x.nested_eq(y, comparing)))
+            }
+            _ => self == other,
+        }
+    }
+}
+
+// Integral numbers print without a decimal point and everything else with the
+// fewest digits that read back to the same f32. Magnitudes of 1e16 and above
+// switch to scientific notation instead of spelling out imprecise digits.
+fn format_number(n: f32) -> String {
+    if !n.is_finite() {
+        format!("{n}")
+    } else if n.abs() >= 1e16 {
+        format!("{n:e}")
+    } else if n.fract() == 0.0 {
+        format!("{}", n as i64)
+    } else {
+        format!("{n}")
+    }
 }
 
 impl PartialEq for Value {
@@ -25,7 +299,21 @@ impl PartialEq for Value {
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+                *a as f32 == *b
+            }
             (Value::DynamicString(a), Value::DynamicString(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Array(_), Value::Array(_)) | (Value::Map(_), Value::Map(_)) => {
+                self.nested_eq(other, &mut HashSet::new())
+            }
+            // Functions are only equal to themselves.
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => a.name == b.name,
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -37,64 +325,125 @@ impl PartialOrd for Value {
             (Value::Boolean(a), Value::Boolean(b)) => a.partial_cmp(b),
             (Value::Nil, Value::Nil) => Some(Ordering::Equal),
             (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Number(b)) => (*a as f32).partial_cmp(b),
+            (Value::Number(a), Value::Int(b)) => a.partial_cmp(&(*b as f32)),
             (Value::DynamicString(a), Value::DynamicString(b)) => a.len().partial_cmp(&b.len()),
             _ => None,
         }
     }
 }
 
+// Arithmetic on incompatible operands is an error rather than a value, so the
+// VM can report it instead of carrying on with a nil. Mixing an integer with a
+// number promotes the integer and produces a number.
+type OpResult = Result<Value, OpError>;
+
+#[derive(Debug)]
+pub enum OpError {
+    // The operand types don't fit the operator; holds what it accepts, for the
+    // VM's message naming the operator.
+    Operands(&'static str),
+    Overflow,
+    // The result would exceed a size limit; holds the whole message.
+    TooLarge(&'static str),
+}
+
+fn int_result(result: Option<i64>) -> OpResult {
+    result.map(Value::Int).ok_or(OpError::Overflow)
+}
+
 impl ops::Add<Value> for Value {
-    type Output = Value;
+    type Output = OpResult;
 
     fn add(self, rhs: Value) -> Self::Output {
         match (self, rhs) {
-            (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a | b),
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::Boolean(a), Value::Boolean(b)) => Ok(Value::Boolean(a | b)),
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            (Value::Int(a), Value::Number(b)) => Ok(Value::Number(a as f32 + b)),
+            (Value::Number(a), Value::Int(b)) => Ok(Value::Number(a + b as f32)),
+            (Value::Int(a), Value::Int(b)) => int_result(a.checked_add(b)),
             (Value::DynamicString(a), Value::DynamicString(b)) => {
-                Value::DynamicString([a, b].concat().replace("\"\"", ""))
+                Ok(Value::DynamicString([a, b].concat()))
             }
-            _ => Value::Nil,
+            (Value::Array(a), Value::Array(b)) => Ok(Value::array(
+                [a.borrow().as_slice(), b.borrow().as_slice()].concat(),
+            )),
+            _ => Err(OpError::Operands(
+                "two numbers, two strings, two booleans or two arrays",
+            )),
         }
     }
 }
 
 impl ops::Sub<Value> for Value {
-    type Output = Value;
+    type Output = OpResult;
 
     fn sub(self, rhs: Value) -> Self::Output {
         match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            (Value::DynamicString(a), Value::DynamicString(b)) => {
-                Value::DynamicString(a.replace(&b, ""))
-            }
-            _ => Value::Nil,
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+            (Value::Int(a), Value::Number(b)) => Ok(Value::Number(a as f32 - b)),
+            (Value::Number(a), Value::Int(b)) => Ok(Value::Number(a - b as f32)),
+            (Value::Int(a), Value::Int(b)) => int_result(a.checked_sub(b)),
+            _ => Err(OpError::Operands("numbers")),
         }
     }
 }
 
 impl ops::Mul<Value> for Value {
-    type Output = Value;
+    type Output = OpResult;
 
     fn mul(self, rhs: Value) -> Self::Output {
         match (self, rhs) {
-            (Value::Boolean(a), Value::Boolean(b)) => Value::Boolean(a & b),
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => Value::Nil,
+            (Value::Boolean(a), Value::Boolean(b)) => Ok(Value::Boolean(a & b)),
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
+            (Value::Int(a), Value::Number(b)) => Ok(Value::Number(a as f32 * b)),
+            (Value::Number(a), Value::Int(b)) => Ok(Value::Number(a * b as f32)),
+            (Value::Int(a), Value::Int(b)) => int_result(a.checked_mul(b)),
+            (Value::DynamicString(s), Value::Number(n))
+            | (Value::Number(n), Value::DynamicString(s)) => repeat(&s, n as i64),
+            (Value::DynamicString(s), Value::Int(n)) | (Value::Int(n), Value::DynamicString(s)) => {
+                repeat(&s, n)
+            }
+            _ => Err(OpError::Operands(
+                "two numbers, two booleans or a string and a number",
+            )),
         }
     }
 }
 
+// Longest string `*` will build; anything longer is an error rather than an
+// attempt to allocate it.
+const MAX_REPEAT_LEN: usize = 1 << 24;
+
+// Repeats a string `count` times. The count is truncated towards zero, so
+// `"ab" * 2.7` yields `"abab"`, and negative counts yield an empty string.
+fn repeat(s: &str, count: i64) -> OpResult {
+    let count = usize::try_from(count.max(0)).unwrap_or(usize::MAX);
+    match s.len().checked_mul(count) {
+        Some(len) if len <= MAX_REPEAT_LEN => Ok(Value::DynamicString(s.repeat(count))),
+        _ => Err(OpError::TooLarge(
+            "String repetition result exceeds 16 MiB.",
+        )),
+    }
+}
+
 impl ops::Div for Value {
-    type Output = Value;
+    type Output = OpResult;
 
     fn div(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-            _ => Value::Nil,
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a / b)),
+            (Value::Int(a), Value::Number(b)) => Ok(Value::Number(a as f32 / b)),
+            (Value::Number(a), Value::Int(b)) => Ok(Value::Number(a / b as f32)),
+            // Division always produces a float, even for two integers.
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Number(a as f32 / b as f32)),
+            _ => Err(OpError::Operands("numbers")),
         }
     }
 }
 
+#[derive(Debug)]
 pub struct ValueArray {
     pub values: Vec<Value>,
 }
@@ -120,9 +469,17 @@ impl ValueArray {
         self.values.clear()
     }
 
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
     pub fn peek(&self, distance: usize) -> Option<&Value> {
-        let index = self.values.len() - 1 - distance;
-        Some(&self.values[index])
+        let index = self.values.len().checked_sub(distance + 1)?;
+        self.values.get(index)
     }
 }
 
@@ -137,3 +494,186 @@ impl fmt::Display for ValueArray {
         write!(f, "{}", output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{output, runtime_error};
+
+    fn string(s: &str) -> Value {
+        Value::DynamicString(s.to_string())
+    }
+
+    #[test]
+    fn multiplying_a_string_repeats_it() {
+        assert_eq!(
+            (string("ab") * Value::Number(3.0)).unwrap(),
+            string("ababab")
+        );
+        assert_eq!((Value::Number(2.0) * string("ab")).unwrap(), string("abab"));
+        assert_eq!((string("ab") * Value::Number(0.0)).unwrap(), string(""));
+    }
+
+    #[test]
+    fn string_repeat_counts_truncate() {
+        assert_eq!((string("ab") * Value::Number(2.7)).unwrap(), string("abab"));
+        assert_eq!((string("ab") * Value::Number(-3.0)).unwrap(), string(""));
+    }
+
+    #[test]
+    fn huge_string_repeat_is_an_error() {
+        let result = string("x") * Value::Number(1e20);
+        assert!(matches!(result, Err(OpError::TooLarge(_))));
+        assert_eq!(
+            runtime_error(r#"print "x" * 100000000;"#),
+            "String repetition result exceeds 16 MiB."
+        );
+    }
+
+    #[test]
+    fn bytes_print_as_a_literal_that_reads_back() {
+        let literal = r#"b"\x00\xff\"a\\\n""#;
+        let printed = output(&format!("print {literal};"));
+        assert_eq!(printed, format!("{literal}\n"));
+        assert_eq!(
+            output(&format!("print {} == {literal};", printed.trim())),
+            "true\n"
+        );
+    }
+
+    #[test]
+    fn numbers_print_without_trailing_zeros() {
+        let printed = |n: f32| Value::Number(n).to_string();
+        assert_eq!(printed(1.0), "1");
+        assert_eq!(printed(1.5), "1.5");
+        assert_eq!(printed(0.1), "0.1");
+        assert_eq!(printed(-0.25), "-0.25");
+        assert_eq!(printed(100000000.0), "100000000");
+        assert_eq!(printed(1.2345679e29), "1.2345679e29");
+        assert_eq!(printed(f32::INFINITY), "inf");
+    }
+
+    #[test]
+    fn functions_print_and_equal_only_themselves() {
+        assert_eq!(
+            output(
+                r#"
+                fun f() {}
+                fun g() {}
+                var h = f;
+                print f;
+                print f == f;
+                print f == g;
+                print h == f;
+                print push;
+                print push == push;
+                print push == pop;
+                "#
+            ),
+            "<fn f>\ntrue\nfalse\ntrue\n<native fn push>\ntrue\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn mixing_integers_and_numbers() {
+        let (int, num) = (Value::Int(6), Value::Number(1.5));
+        let cases = [
+            (int.clone() + int.clone(), Value::Int(12)),
+            (int.clone() + num.clone(), Value::Number(7.5)),
+            (num.clone() + int.clone(), Value::Number(7.5)),
+            (int.clone() - num.clone(), Value::Number(4.5)),
+            (num.clone() - int.clone(), Value::Number(-4.5)),
+            (int.clone() - int.clone(), Value::Int(0)),
+            (int.clone() * num.clone(), Value::Number(9.0)),
+            (num.clone() * int.clone(), Value::Number(9.0)),
+            (int.clone() * int.clone(), Value::Int(36)),
+            (int.clone() / num.clone(), Value::Number(4.0)),
+            (num.clone() / int.clone(), Value::Number(0.25)),
+            (Value::Int(7) / Value::Int(2), Value::Number(3.5)),
+        ];
+        for (result, expected) in cases {
+            let result = result.unwrap();
+            assert_eq!(result, expected);
+            // Equality alone would let an integer stand in for a number.
+            assert_eq!(
+                matches!(result, Value::Int(_)),
+                matches!(expected, Value::Int(_))
+            );
+        }
+
+        assert_eq!(Value::Int(2), Value::Number(2.0));
+        assert_eq!(Value::Number(2.0), Value::Int(2));
+        assert!(Value::Int(2) < Value::Number(2.5));
+        assert!(Value::Number(2.5) > Value::Int(2));
+        assert!(Value::Int(2) <= Value::Int(2));
+        assert!(matches!(
+            Value::Int(i64::MAX) + Value::Int(1),
+            Err(OpError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn adding_arrays_concatenates_them() {
+        assert_eq!(
+            output("var a = [1, 2]; var b = a + [3]; push(b, 4); print a; print b; print [] + [];"),
+            "[1, 2]\n[1, 2, 3, 4]\n[]\n"
+        );
+        let array = Value::array(vec![Value::Number(1.0)]);
+        for result in [
+            array.clone() + Value::Number(2.0),
+            Value::Number(2.0) + array,
+        ] {
+            assert!(matches!(result, Err(OpError::Operands(_))));
+        }
+    }
+
+    #[test]
+    fn maps_print_in_key_order_however_they_were_built() {
+        assert_eq!(
+            output(
+                r#"
+                var literal = {"b": 2, "a": 1, "c": 3};
+                var assigned = {};
+                assigned["c"] = 3;
+                assigned["a"] = 1;
+                assigned["b"] = 2;
+                print literal;
+                print assigned;
+                "#
+            ),
+            "{a: 1, b: 2, c: 3}\n{a: 1, b: 2, c: 3}\n"
+        );
+    }
+
+    #[test]
+    fn classes_print_their_name() {
+        assert_eq!(
+            output("class Foo {} print Foo; print Foo();"),
+            "Foo\nFoo instance\n"
+        );
+    }
+
+    #[test]
+    fn arrays_and_maps_containing_themselves_print_and_compare() {
+        assert_eq!(
+            output(
+                r#"
+                var a = [1];
+                push(a, a);
+                print a;
+                print a == a;
+                var m = {"k": 1};
+                m["self"] = m;
+                m["list"] = [m];
+                print m;
+                print m == m;
+                var b = [1];
+                push(b, b);
+                print a == b;
+                print [a, a];
+                "#
+            ),
+            "[1, [...]]\ntrue\n{k: 1, list: [{...}], self: {...}}\ntrue\ntrue\n[[1, [...]], [1, [...]]]\n"
+        );
+    }
+}
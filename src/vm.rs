@@ -1,98 +1,416 @@
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
 
 use crate::{
-    chunk::{display, Chunk, OpCode},
-    compiler,
-    value::{Value, ValueArray},
+    chunk::{display, format_instruction, OpCode},
+    compiler::{self, CompileOptions, Diagnostic, ErrorCategory},
+    natives,
+    scanner::{Scanner, TokenType},
+    value::{
+        BoundMethod, Class, Function, Instance, NativeError, NativeFunction, OpError, Value,
+        ValueArray, VARIADIC,
+    },
 };
+use arcstr::ArcStr;
 use colored::Colorize;
-use custom_error::custom_error;
+use rprompt::prompt_reply;
+
+const FRAMES_MAX: usize = 64;
+
+thread_local! {
+    // Offset and line of the instruction being run, kept in debug builds so
+    // the panic hook can say where in the script a panic happened.
+    static CURRENT_INSTRUCTION: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
 
-pub struct Vm<'a> {
-    chunk: &'a Chunk,
+// Reports the running instruction before the usual panic message.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(context) = panic_context() {
+            eprintln!("{context}");
+        }
+        default_hook(info);
+    }));
+}
+
+fn panic_context() -> Option<String> {
+    CURRENT_INSTRUCTION
+        .with(Cell::get)
+        .map(|(offset, line)| format!("VM panicked at offset {offset} [line {line}]."))
+}
+const STEP_STACK_SLOTS: usize = 4;
+
+struct CallFrame {
+    function: Rc<Function>,
     ip: usize,
+    slot_base: usize,
+}
+
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: ValueArray,
-    globals: HashMap<String, Value>,
+    // Global values by slot; a slot is empty until its global is defined.
+    globals: Vec<Option<Value>>,
+    // Slot of each global name. The compiler adds to it when it resolves
+    // declarations, so compiled code can index `globals` directly.
+    global_slots: HashMap<String, usize>,
+    // Slots of globals defined with `const`, which can't be assigned or redefined.
+    const_globals: HashSet<usize>,
+    step: bool,
+    // Instructions executed so far, checked against the optional limit.
+    steps: u64,
+    max_steps: Option<u64>,
+    // State of the xorshift generator behind the random natives; never zero.
+    rng: u64,
+    // Whether the last script ran a `return` of its own.
+    script_returned: bool,
+    // Everything a script prints goes here, so output handling lives in one place.
+    output: Box<dyn Write>,
+    // Whether runtime errors are printed with a stack trace as they happen.
+    print_errors: bool,
+    // Where those runtime errors are printed.
+    error_output: Box<dyn Write>,
+    // Receives a line per executed instruction when tracing to a file.
+    trace_log: Option<Box<dyn Write>>,
 }
 
-custom_error! { pub InterpretError
-    CompileError = "Error during compilation.",
-    RuntimeError = "Error during execution",
+#[derive(Debug)]
+pub enum InterpretError {
+    // `category` is None when no error was reported, such as when only
+    // warnings failed the compile, or when it isn't known.
+    CompileError {
+        diagnostics: Vec<Diagnostic>,
+        category: Option<ErrorCategory>,
+    },
+    RuntimeError {
+        message: String,
+        line: usize,
+    },
 }
 
-impl<'a> Vm<'a> {
-    pub fn init(chunk: &'a Chunk) -> Self {
-        Vm {
-            chunk,
-            ip: 0,
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpretError::CompileError { diagnostics, .. } => {
+                write!(f, "Error during compilation.")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{diagnostic}")?;
+                }
+                Ok(())
+            }
+            InterpretError::RuntimeError { message, line } => write!(f, "[line {line}] {message}"),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+impl Vm {
+    pub fn init() -> Self {
+        let mut vm = Vm {
+            frames: Vec::new(),
             stack: ValueArray::init(),
-            globals: HashMap::new(),
+            globals: Vec::new(),
+            global_slots: HashMap::new(),
+            const_globals: HashSet::new(),
+            step: false,
+            steps: 0,
+            max_steps: None,
+            rng: 0,
+            script_returned: false,
+            output: Box::new(io::stdout()),
+            print_errors: true,
+            error_output: Box::new(io::stderr()),
+            trace_log: None,
+        };
+        vm.reset();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        vm.seed_random(now);
+        vm
+    }
+
+    // Sets up a compiled script to run next. Globals from earlier scripts are kept.
+    pub fn load(&mut self, function: Rc<Function>) {
+        self.stack.push(Value::Function(function.clone()));
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slot_base: 0,
+        });
+    }
+
+    // Clears the stack and all globals, leaving only the natives defined.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.stack.reset();
+        self.globals.clear();
+        self.global_slots.clear();
+        self.const_globals.clear();
+        self.steps = 0;
+
+        for native in natives::NATIVES {
+            self.define_native(native.clone());
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Flushes the output and the trace log, if any.
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        if let Some(trace_log) = &mut self.trace_log {
+            trace_log.flush()?;
+        }
+        self.output.flush()
+    }
+
+    // Writes a value and a newline to the output sink.
+    pub fn print(&mut self, value: &Value) -> io::Result<()> {
+        writeln!(self.output, "{value}")
+    }
+
+    pub fn seed_random(&mut self, seed: u64) {
+        // Scramble the seed so small seeds don't start with a run of small values.
+        self.rng = (seed ^ 0x9e37_79b9_7f4a_7c15).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        if self.rng == 0 {
+            self.rng = 0x9e37_79b9_7f4a_7c15;
+        }
+    }
+
+    // Advances the xorshift64* generator.
+    pub fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn stack(&self) -> &ValueArray {
+        &self.stack
+    }
+
+    // Defined globals with their values, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.global_slots
+            .iter()
+            .filter_map(|(name, slot)| Some((name, self.globals.get(*slot)?.as_ref()?)))
+    }
+
+    pub fn global_slots_mut(&mut self) -> &mut HashMap<String, usize> {
+        &mut self.global_slots
+    }
+
+    // Returns the slot of a global, giving it the next free one if it has none.
+    fn global_slot(&mut self, name: String) -> usize {
+        let next = self.global_slots.len();
+        *self.global_slots.entry(name).or_insert(next)
+    }
+
+    fn global(&self, name: &str) -> Option<&Value> {
+        let slot = *self.global_slots.get(name)?;
+        self.globals.get(slot)?.as_ref()
+    }
+
+    fn define_global(&mut self, slot: usize, value: Value) {
+        if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
+        }
+        self.globals[slot] = Some(value);
+    }
+
+    // Stores the top of the stack into a defined global that isn't const.
+    fn assign_global(&mut self, slot: usize) -> Result<(), InterpretError> {
+        if self.const_globals.contains(&slot) {
+            let message = format!("Cannot assign to const '{}'.", self.global_name(slot));
+            return Err(self.runtime_error(&message));
+        }
+
+        match (self.globals.get(slot), self.stack.peek(0)) {
+            (Some(Some(_)), Some(value)) => {
+                self.globals[slot] = Some(value.clone());
+                Ok(())
+            }
+            (_, None) => Err(self.runtime_error("Stack underflow.")),
+            (_, Some(_)) => Err(self.runtime_error("Unknown variable.")),
+        }
+    }
+
+    fn global_name(&self, slot: usize) -> &str {
+        self.global_slots
+            .iter()
+            .find(|(_, s)| **s == slot)
+            .map_or("?", |(name, _)| name)
+    }
+
+    pub fn set_step(&mut self, step: bool) {
+        self.step = step;
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    pub fn set_print_errors(&mut self, print_errors: bool) {
+        self.print_errors = print_errors;
+    }
+
+    pub fn set_error_output(&mut self, error_output: Box<dyn Write>) {
+        self.error_output = error_output;
+    }
+
+    pub fn set_trace_log(&mut self, trace_log: Option<Box<dyn Write>>) {
+        self.trace_log = trace_log;
+    }
+
+    pub fn interpret(&mut self) -> Result<Value, InterpretError> {
+        let result = self.run(0);
+        CURRENT_INSTRUCTION.with(|current| current.set(None));
+        result
+    }
+
+    // Calls a function value from native code, running it to completion.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Result<Value, NativeError> {
+        let depth = self.frames.len();
+        self.stack.push(callee.clone());
+        for arg in args {
+            self.stack.push(arg.clone());
         }
+
+        self.call_value(callee, args.len())
+            .map_err(NativeError::Reported)?;
+        if self.frames.len() == depth {
+            // Natives complete immediately and leave their result on the stack.
+            return Ok(self.stack.pop().unwrap_or(Value::Nil));
+        }
+
+        self.run(depth).map_err(NativeError::Reported)
     }
 
-    pub fn interpret(&mut self) -> Result<(), InterpretError> {
-        if cfg!(debug_assertions) {
+    // Executes instructions until the frame count drops back to `base_depth`.
+    fn run(&mut self, base_depth: usize) -> Result<Value, InterpretError> {
+        if cfg!(debug_assertions) && base_depth == 0 {
             println!(
                 "{}",
                 "\nVM Running\n".to_string().magenta().underline().bold()
             );
         }
 
-        while self.ip < self.chunk.code.len() {
+        while self.frame().ip < self.frame().function.chunk.code.len() {
             let instruction = self.read_instruction()?;
 
+            self.steps += 1;
+            if self
+                .max_steps
+                .is_some_and(|max_steps| self.steps > max_steps)
+            {
+                return Err(self.runtime_error("Instruction limit exceeded."));
+            }
+
             if cfg!(debug_assertions) {
                 println!("{}", format!("{} top", self.stack).truecolor(234, 142, 68));
-                display(self.chunk, Some(&instruction), self.ip - 1, "");
+                let frame = self.frame();
+                let offset = frame.ip - 1;
+                let line = frame.function.chunk.line_at(offset).unwrap_or(0);
+                CURRENT_INSTRUCTION.with(|current| current.set(Some((offset, line))));
+                display(&frame.function.chunk, Some(&instruction), offset, "");
+            }
+
+            if self.trace_log.is_some() {
+                self.trace(&instruction)?;
+            }
+
+            if self.step && !self.step_prompt(&instruction) {
+                return Ok(Value::Nil);
             }
 
             match instruction {
                 OpCode::Return => {
-                    return Ok(());
+                    let result = self.stack.pop().unwrap_or(Value::Nil);
+                    let frame = self.frames.pop().expect("No active call frame.");
+                    self.stack.truncate(frame.slot_base);
+                    if self.frames.len() == base_depth {
+                        // The implicit return is the script's last instruction.
+                        self.script_returned =
+                            self.frames.is_empty() && frame.ip < frame.function.chunk.code.len();
+                        return Ok(result);
+                    }
+
+                    self.stack.push(result);
                 }
 
                 OpCode::Constant => {
-                    let constant = self.read_constant();
+                    let constant = self.read_constant()?;
                     self.stack.push(constant);
                 }
 
-                OpCode::Negate => {
-                    let value = self.stack.pop();
-                    match value {
-                        Some(v) => {
-                            if let Value::Number(n) = v {
-                                self.stack.push(Value::Number(-n))
-                            } else {
-                                self.runtime_error("Operand must be a number");
-                                return Err(InterpretError::RuntimeError);
-                            }
-                        }
+                OpCode::Negate => match self.stack.pop() {
+                    Some(Value::Number(n)) => self.stack.push(Value::Number(-n)),
+                    Some(Value::Int(n)) => match n.checked_neg() {
+                        Some(n) => self.stack.push(Value::Int(n)),
                         None => {
-                            println!("Stack Underflow");
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Integer overflow in negation."));
                         }
+                    },
+                    Some(_) => {
+                        return Err(self.runtime_error("Operand must be a number"));
+                    }
+                    None => {
+                        return Err(self.runtime_error("Stack Underflow"));
                     }
-                }
-
-                OpCode::Add => match self.binary_op(|a, b| a + b) {
-                    Ok(v) => self.stack.push(v),
-                    Err(_) => return Err(InterpretError::RuntimeError),
-                },
-                OpCode::Subtract => match self.binary_op(|a, b| a - b) {
-                    Ok(v) => self.stack.push(v),
-                    Err(_) => return Err(InterpretError::RuntimeError),
-                },
-                OpCode::Divide => match self.binary_op(|a, b| a / b) {
-                    Ok(v) => self.stack.push(v),
-                    Err(_) => return Err(InterpretError::RuntimeError),
                 },
+                OpCode::Add => {
+                    let value = self.binary_op("+", |a, b| a + b)?;
+                    self.stack.push(value);
+                }
+                OpCode::Subtract => {
+                    let value = self.binary_op("-", |a, b| a - b)?;
+                    self.stack.push(value);
+                }
+                OpCode::Divide => {
+                    let value = self.binary_op("/", |a, b| a / b)?;
+                    self.stack.push(value);
+                }
                 OpCode::Multiply => {
-                    let value = self.binary_op(|a, b| a * b)?;
+                    let value = self.binary_op("*", |a, b| a * b)?;
                     self.stack.push(value);
                 }
+                OpCode::Constant0 => self.stack.push(Value::Number(0.0)),
+                OpCode::Constant1 => self.stack.push(Value::Number(1.0)),
+                OpCode::ConstantMinus1 => self.stack.push(Value::Number(-1.0)),
                 OpCode::True => self.stack.push(Value::Boolean(true)),
                 OpCode::False => self.stack.push(Value::Boolean(false)),
                 OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::AssertNumber => {
+                    if !matches!(self.stack.peek(0), Some(Value::Number(_) | Value::Int(_))) {
+                        return Err(self.runtime_error("Operand must be a number"));
+                    }
+                }
+                OpCode::Swap => {
+                    // Slot zero of the frame holds the callee and is never swapped.
+                    let len = self.stack.values.len();
+                    if len < self.frame().slot_base + 3 {
+                        return Err(self.runtime_error("Stack underflow."));
+                    }
+                    self.stack.values.swap(len - 1, len - 2);
+                }
+                OpCode::Dup => match self.stack.peek(0) {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => return Err(self.runtime_error("Stack underflow.")),
+                },
+                OpCode::CheckNumber => match self.stack.peek(0) {
+                    Some(Value::Number(_) | Value::Int(_)) => {}
+                    Some(_) => return Err(self.runtime_error("Range bounds must be numbers.")),
+                    None => return Err(self.runtime_error("Stack underflow.")),
+                },
                 OpCode::Not => {
                     let v = self.stack.pop();
                     if let Some(v) = v {
@@ -102,8 +420,7 @@ impl<'a> Vm<'a> {
                             _ => self.stack.push(Value::Boolean(false)),
                         }
                     } else {
-                        self.runtime_error("Stack underflow");
-                        return Err(InterpretError::RuntimeError);
+                        return Err(self.runtime_error("Stack underflow"));
                     }
                 }
                 OpCode::Equal => {
@@ -126,86 +443,441 @@ impl<'a> Vm<'a> {
                 }
                 OpCode::Print => {
                     if let Some(v) = self.stack.pop() {
-                        println!("{v}");
+                        if let Err(e) = self.print(&v) {
+                            return Err(self.runtime_error(&format!("Failed to print: {e}.")));
+                        }
                     } else {
-                        println!("Stack Underflow");
-                        return Err(InterpretError::RuntimeError);
+                        return Err(self.runtime_error("Stack Underflow"));
                     }
                 }
                 OpCode::Pop => {
                     let v = self.stack.pop();
                     if v.is_none() {
-                        println!("Stack Underflow");
-                        return Err(InterpretError::RuntimeError);
+                        return Err(self.runtime_error("Stack Underflow"));
+                    }
+                }
+                OpCode::PopN => {
+                    let count = self.read_byte()? as usize;
+                    if self.stack.values.len() < count {
+                        return Err(self.runtime_error("Stack Underflow"));
+                    }
+                    let len = self.stack.values.len() - count;
+                    self.stack.truncate(len);
+                }
+                OpCode::BuildArray => {
+                    let count = self.read_byte()? as usize;
+                    if self.stack.values.len() < count {
+                        return Err(self.runtime_error("Stack Underflow"));
+                    }
+                    let start = self.stack.values.len() - count;
+                    let values = self.stack.values.split_off(start);
+                    self.stack.push(Value::array(values));
+                }
+                OpCode::BuildMap => {
+                    let count = self.read_byte()? as usize;
+                    if self.stack.values.len() < count * 2 {
+                        return Err(self.runtime_error("Stack Underflow"));
+                    }
+                    let start = self.stack.values.len() - count * 2;
+                    let values = self.stack.values.split_off(start);
+                    let mut entries = HashMap::with_capacity(count);
+                    for pair in values.chunks(2) {
+                        match &pair[0] {
+                            Value::DynamicString(key) => {
+                                entries.insert(key.clone(), pair[1].clone());
+                            }
+                            _ => {
+                                return Err(self.runtime_error("Map keys must be strings."));
+                            }
+                        }
+                    }
+                    self.stack.push(Value::map(entries));
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short()?;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short()?;
+                    if self.stack.peek(0).is_none_or(is_falsey) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short()?;
+                    let Some(ip) = self.frame_mut().ip.checked_sub(offset) else {
+                        return Err(self.runtime_error(
+                            "Corrupt bytecode: loop jumps before the start of the chunk.",
+                        ));
+                    };
+                    self.frame_mut().ip = ip;
+                }
+                OpCode::GetIndex => {
+                    let index = self.stack.pop();
+                    let container = self.stack.pop();
+                    let value = match (container, index) {
+                        (Some(container), Some(index)) => self.index_value(container, index)?,
+                        _ => {
+                            return Err(self.runtime_error("Stack Underflow"));
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.stack.pop();
+                    let index = self.stack.pop();
+                    let container = self.stack.pop();
+                    match (container, index, value) {
+                        (Some(container), Some(index), Some(value)) => {
+                            self.set_index(container, index, value.clone())?;
+                            self.stack.push(value);
+                        }
+                        _ => return Err(self.runtime_error("Stack underflow.")),
                     }
                 }
-                OpCode::DefineGlobal => {
-                    let name = self.read_constant();
+                OpCode::Dup2 => match (self.stack.peek(1), self.stack.peek(0)) {
+                    (Some(a), Some(b)) => {
+                        let (a, b) = (a.clone(), b.clone());
+                        self.stack.push(a);
+                        self.stack.push(b);
+                    }
+                    _ => return Err(self.runtime_error("Stack underflow.")),
+                },
+                OpCode::Length => {
+                    let length = match self.stack.pop() {
+                        Some(Value::Array(values)) => values.borrow().len(),
+                        Some(Value::Bytes(bytes)) => bytes.len(),
+                        _ => {
+                            return Err(
+                                self.runtime_error("Can only iterate over arrays and bytes.")
+                            );
+                        }
+                    };
+                    self.stack.push(Value::Number(length as f32));
+                }
+                op @ (OpCode::DefineGlobal | OpCode::DefineGlobalConst) => {
+                    let name = self.read_constant()?;
 
                     match name {
                         Value::DynamicString(name) => {
-                            if let Some(v) = self.stack.peek(0) {
-                                self.globals.insert(name, v.clone());
-                                self.stack.pop();
+                            if let Some(v) = self.stack.pop() {
+                                let slot = self.global_slot(name);
+                                if self.const_globals.contains(&slot) {
+                                    let message = format!(
+                                        "Cannot redeclare const '{}'.",
+                                        self.global_name(slot)
+                                    );
+                                    return Err(self.runtime_error(&message));
+                                }
+                                if op == OpCode::DefineGlobalConst {
+                                    self.const_globals.insert(slot);
+                                }
+                                self.define_global(slot, v);
                             }
                         }
                         _ => {
-                            println!("Variable specifier must be a string.");
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Variable specifier must be a string."));
+                        }
+                    }
+                }
+                OpCode::Class => match self.read_constant()? {
+                    Value::DynamicString(name) => {
+                        self.stack.push(Value::Class(Rc::new(Class::init(&name))))
+                    }
+                    _ => return Err(self.runtime_error("Class name must be a string.")),
+                },
+                OpCode::GetProperty => {
+                    let name = self.read_constant()?;
+                    let value = match (self.stack.pop(), name) {
+                        (Some(Value::Instance(instance)), Value::DynamicString(name)) => {
+                            let field = instance.fields.borrow().get(&name).cloned();
+                            let method = instance.class.methods.borrow().get(&name).cloned();
+                            match (field, method) {
+                                (Some(value), _) => value,
+                                (None, Some(method)) => Value::BoundMethod(Rc::new(BoundMethod {
+                                    receiver: Value::Instance(instance),
+                                    method,
+                                })),
+                                (None, None) => {
+                                    return Err(self
+                                        .runtime_error(&format!("Undefined property '{name}'.")))
+                                }
+                            }
+                        }
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_constant()?;
+                    let value = self.stack.pop();
+                    match (self.stack.pop(), name, value) {
+                        (
+                            Some(Value::Instance(instance)),
+                            Value::DynamicString(name),
+                            Some(value),
+                        ) => {
+                            instance.fields.borrow_mut().insert(name, value.clone());
+                            self.stack.push(value);
                         }
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    }
+                }
+                OpCode::Method => {
+                    let name = self.read_constant()?;
+                    let method = self.stack.pop();
+                    match (self.stack.peek(0), name, method) {
+                        (
+                            Some(Value::Class(class)),
+                            Value::DynamicString(name),
+                            Some(Value::Function(method)),
+                        ) => {
+                            class.methods.borrow_mut().insert(name, method);
+                        }
+                        _ => return Err(self.runtime_error("Methods must be defined on a class.")),
                     }
                 }
                 OpCode::GetGlobal => {
-                    let name = self.read_constant();
+                    let name = self.read_constant()?;
 
                     match name {
                         Value::DynamicString(name) => {
-                            let value = self.globals.get(&name);
-                            if let Some(value) = value {
+                            if let Some(value) = self.global(&name) {
                                 self.stack.push(value.clone());
                             } else {
-                                println!("Variable {name} is not known.");
-                                return Err(InterpretError::RuntimeError);
+                                return Err(
+                                    self.runtime_error(&format!("Variable {name} is not known."))
+                                );
                             }
                         }
                         _ => {
-                            println!("Variable specifier must be a string.");
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Variable specifier must be a string."));
                         }
                     }
                 }
                 OpCode::SetGlobal => {
-                    let name = self.read_constant();
+                    let name = self.read_constant()?;
 
                     match name {
-                        Value::DynamicString(name) => {
-                            match (self.globals.get(&name.clone()), self.stack.peek(0)) {
-                                (None, None) => return Err(InterpretError::RuntimeError),
-                                (None, Some(_)) => {
-                                    println!("Unknown variable.");
-                                    return Err(InterpretError::RuntimeError);
-                                }
-                                (Some(_), None) => {
-                                    println!("Stack underflow.");
-                                    return Err(InterpretError::RuntimeError);
-                                }
-                                (Some(_), Some(value)) => {
-                                    self.globals.insert(name.to_string(), value.clone());
-                                }
-                            }
+                        Value::DynamicString(name) => match self.global_slots.get(&name) {
+                            Some(slot) => self.assign_global(*slot)?,
+                            None => return Err(self.runtime_error("Unknown variable.")),
+                        },
+                        _ => {
+                            return Err(self.runtime_error("Variable specifier must be a string."));
                         }
+                    }
+                }
+                OpCode::GetGlobalFast => {
+                    let slot = self.read_byte()? as usize;
+                    match self.globals.get(slot) {
+                        Some(Some(value)) => self.stack.push(value.clone()),
                         _ => {
-                            println!("Variable specifier must be a string.");
-                            return Err(InterpretError::RuntimeError);
+                            let message =
+                                format!("Variable {} is not known.", self.global_name(slot));
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                }
+                OpCode::SetGlobalFast => {
+                    let slot = self.read_byte()? as usize;
+                    self.assign_global(slot)?;
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_local_slot()?;
+                    let value = self.stack.values[slot].clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_local_slot()?;
+                    if let Some(value) = self.stack.peek(0) {
+                        self.stack.values[slot] = value.clone();
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte()? as usize;
+                    let callee = self.stack.peek(arg_count).cloned();
+                    match callee {
+                        Some(callee) => self.call_value(callee, arg_count)?,
+                        None => {
+                            return Err(self.runtime_error("Stack underflow"));
                         }
                     }
                 }
             }
         }
 
+        Ok(Value::Nil)
+    }
+
+    fn index_value(&mut self, container: Value, index: Value) -> Result<Value, InterpretError> {
+        // Looking up a missing key yields nil rather than an error.
+        if let Value::Map(entries) = &container {
+            return match index {
+                Value::DynamicString(key) => {
+                    Ok(entries.borrow().get(&key).cloned().unwrap_or(Value::Nil))
+                }
+                _ => Err(self.runtime_error("Map keys must be strings.")),
+            };
+        }
+
+        let value = match (&container, index.as_integer()) {
+            (Value::Array(values), Some(n)) => {
+                let values = values.borrow();
+                array_index(n, values.len()).map(|i| values[i].clone())
+            }
+            (Value::Array(_), None) => {
+                return Err(self.runtime_error("Index must be an integer."));
+            }
+            (Value::Bytes(bytes), Some(n)) if n >= 0 => usize::try_from(n)
+                .ok()
+                .and_then(|n| bytes.get(n))
+                .map(|b| Value::Number(*b as f32)),
+            (Value::Bytes(_), _) => {
+                return Err(self.runtime_error("Index must be a non-negative integer."));
+            }
+            _ => {
+                return Err(self.runtime_error("Can only index arrays, bytes and maps."));
+            }
+        };
+
+        match value {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Index out of bounds.")),
+        }
+    }
+
+    fn set_index(
+        &mut self,
+        container: Value,
+        index: Value,
+        value: Value,
+    ) -> Result<(), InterpretError> {
+        match (container, index) {
+            (Value::Map(entries), Value::DynamicString(key)) => {
+                entries.borrow_mut().insert(key, value);
+                Ok(())
+            }
+            (Value::Map(_), _) => Err(self.runtime_error("Map keys must be strings.")),
+            (Value::Array(values), index) => {
+                let Some(n) = index.as_integer() else {
+                    return Err(self.runtime_error("Index must be an integer."));
+                };
+                let mut values = values.borrow_mut();
+                match array_index(n, values.len()) {
+                    Some(i) => values[i] = value,
+                    None => return Err(self.runtime_error("Index out of bounds.")),
+                }
+                Ok(())
+            }
+            _ => Err(self.runtime_error("Can only assign to array and map elements.")),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        match callee {
+            Value::Function(function) => self.call(function, arg_count),
+            Value::NativeFunction(native) => self.call_native(native, arg_count),
+            Value::Class(class) => {
+                if arg_count != 0 {
+                    return Err(self.runtime_error(&format!(
+                        "Expected 0 arguments but got {} in call to {}.",
+                        arg_count, class.name
+                    )));
+                }
+                let instance = Value::Instance(Rc::new(Instance::init(class)));
+                let callee_slot = self.stack.values.len() - 1;
+                self.stack.values[callee_slot] = instance;
+                Ok(())
+            }
+            Value::BoundMethod(bound) => {
+                let callee_slot = self.stack.values.len() - arg_count - 1;
+                self.stack.values[callee_slot] = bound.receiver.clone();
+                self.call(bound.method.clone(), arg_count)
+            }
+            _ => Err(self.runtime_error("Can only call functions.")),
+        }
+    }
+
+    fn call(&mut self, function: Rc<Function>, arg_count: usize) -> Result<(), InterpretError> {
+        if arg_count != function.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {} in call to {}.",
+                function.arity, arg_count, function.name
+            )));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slot_base: self.stack.values.len() - arg_count - 1,
+        });
         Ok(())
     }
 
+    fn call_native(
+        &mut self,
+        native: NativeFunction,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        if native.arity != VARIADIC && arg_count != native.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {} in call to {}.",
+                native.arity, arg_count, native.name
+            )));
+        }
+
+        let args_start = self.stack.values.len() - arg_count;
+        let args = self.stack.values[args_start..].to_vec();
+        match (native.function)(self, &args) {
+            Ok(result) => {
+                self.stack.truncate(args_start - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Err(NativeError::Message(message)) => Err(self.runtime_error(&message)),
+            Err(NativeError::Reported(error)) => Err(error),
+        }
+    }
+
+    fn define_native(&mut self, native: NativeFunction) {
+        let slot = self.global_slot(native.name.to_string());
+        self.define_global(slot, Value::NativeFunction(native));
+    }
+
+    // Shows the next instruction and waits for Enter, `c` to continue or `q` to quit.
+    // Returns false when the user quits.
+    fn step_prompt(&mut self, instruction: &OpCode) -> bool {
+        let frame = self.frame();
+        display(&frame.function.chunk, Some(instruction), frame.ip - 1, "");
+
+        let mut top = ValueArray::init();
+        let skip = self.stack.values.len().saturating_sub(STEP_STACK_SLOTS);
+        for value in &self.stack.values[skip..] {
+            top.push(value.clone());
+        }
+        println!("{top} top");
+
+        match prompt_reply("step> ").unwrap_or_default().trim() {
+            "c" => self.step = false,
+            "q" => return false,
+            _ => {}
+        }
+        true
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No active call frame.")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No active call frame.")
+    }
+
     fn equal_op(&mut self) -> Result<Value, InterpretError> {
         let b = self.stack.pop();
         let a = self.stack.pop();
@@ -213,7 +885,7 @@ impl<'a> Vm<'a> {
         if let (Some(a), Some(b)) = (a, b) {
             Ok(Value::Boolean(a == b))
         } else {
-            Err(InterpretError::RuntimeError)
+            Err(self.runtime_error("Stack underflow."))
         }
     }
 
@@ -224,56 +896,700 @@ impl<'a> Vm<'a> {
         if let (Some(a), Some(b)) = (a, b) {
             Ok(Value::Boolean(op(a, b)))
         } else {
-            Err(InterpretError::RuntimeError)
+            Err(self.runtime_error("Stack underflow."))
         }
     }
 
-    fn binary_op<F: Fn(Value, Value) -> Value>(&mut self, op: F) -> Result<Value, InterpretError> {
+    // Applies an arithmetic operator, naming it in any type error.
+    fn binary_op<F: Fn(Value, Value) -> Result<Value, OpError>>(
+        &mut self,
+        symbol: &str,
+        op: F,
+    ) -> Result<Value, InterpretError> {
         let b = self.stack.pop();
         let a = self.stack.pop();
 
-        match (a, b) {
-            (Some(a), Some(b)) => Ok(op(a, b)),
-            _ => {
-                self.runtime_error("Operand must be a number");
-                Err(InterpretError::RuntimeError)
+        let (Some(a), Some(b)) = (a, b) else {
+            return Err(self.runtime_error("Stack underflow."));
+        };
+        op(a, b).map_err(|error| match error {
+            OpError::Operands(expected) => {
+                self.runtime_error(&format!("Operands to '{symbol}' must be {expected}."))
             }
+            OpError::Overflow => self.runtime_error("Integer overflow."),
+            OpError::TooLarge(message) => self.runtime_error(message),
+        })
+    }
+
+    fn read_byte(&mut self) -> Result<u8, InterpretError> {
+        let frame = self.frames.last_mut().expect("No active call frame.");
+        match frame.function.chunk.code.get(frame.ip) {
+            Some(byte) => {
+                frame.ip += 1;
+                Ok(*byte)
+            }
+            None => Err(self.runtime_error("Corrupt bytecode: unexpected end of chunk.")),
         }
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
-        byte
+    fn read_short(&mut self) -> Result<usize, InterpretError> {
+        let high = self.read_byte()? as usize;
+        let low = self.read_byte()? as usize;
+        Ok((high << 8) | low)
     }
 
-    fn read_constant(&mut self) -> Value {
-        let index = self.read_byte();
+    // Reads a local slot operand and resolves it to a stack index, which must
+    // lie within the current frame's window of the stack.
+    fn read_local_slot(&mut self) -> Result<usize, InterpretError> {
+        let slot = self.frame().slot_base + self.read_byte()? as usize;
+        if slot >= self.stack.values.len() {
+            let offset = self.frame().ip - 2;
+            let message = match self.frame().function.chunk.debug_name(offset) {
+                Some(name) => format!("Invalid local slot for '{name}'."),
+                None => "Invalid local slot.".to_string(),
+            };
+            return Err(self.runtime_error(&message));
+        }
+        Ok(slot)
+    }
+
+    fn read_constant(&mut self) -> Result<Value, InterpretError> {
+        let index = self.read_byte()? as usize;
 
-        self.chunk.constants.values[index as usize].clone()
+        match self.frame().function.chunk.constant(index) {
+            Some(value) => Ok(value.clone()),
+            None => Err(self.runtime_error(&format!(
+                "Corrupt bytecode: constant index {index} out of range."
+            ))),
+        }
     }
 
     fn read_instruction(&mut self) -> Result<OpCode, InterpretError> {
-        let byte = self.read_byte();
+        let byte = self.read_byte()?;
         let instruction = OpCode::try_from(byte);
         match instruction {
             Ok(value) => Ok(value),
-            Err(_) => Err(InterpretError::RuntimeError),
+            Err(_) => Err(self.runtime_error(&format!("Corrupt bytecode: unknown opcode {byte}."))),
         }
     }
 
-    fn runtime_error(&mut self, arg: &str) {
-        eprintln!("{}", arg);
+    // Logs the instruction about to run and the stack it will run on. Every
+    // entry names its line, since looking up the one before is a scan of the chunk.
+    fn trace(&mut self, instruction: &OpCode) -> Result<(), InterpretError> {
+        let frame = self.frame();
+        let line = format_instruction(
+            &frame.function.chunk,
+            Some(instruction),
+            frame.ip - 1,
+            None,
+            "",
+            false,
+        );
+        let stack = self.stack.to_string();
+        let written = match &mut self.trace_log {
+            Some(trace_log) => writeln!(trace_log, "{line}\t{stack}"),
+            None => Ok(()),
+        };
+        written.map_err(|e| self.runtime_error(&format!("Failed to write trace log: {e}.")))
+    }
+
+    // Reports a runtime error with a stack trace and resets the VM, returning
+    // the error for the caller to propagate.
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
+        // A failure to write the report leaves nothing better to report it to.
+        if self.print_errors {
+            let _ = writeln!(self.error_output, "{}", message);
+        }
 
-        let line = self.chunk.lines[self.ip - 1];
-        eprintln!("[line {line}] in script");
+        let mut innermost_line = None;
+        for frame in self.frames.iter().rev() {
+            let line = frame
+                .ip
+                .checked_sub(1)
+                .and_then(|i| frame.function.chunk.line_at(i))
+                .unwrap_or(0);
+            innermost_line.get_or_insert(line);
+            if !self.print_errors {
+                continue;
+            }
+            if frame.function.name.is_empty() {
+                let _ = writeln!(self.error_output, "[line {line}] in script");
+            } else {
+                let _ = writeln!(
+                    self.error_output,
+                    "[line {line}] in {}()",
+                    frame.function.name
+                );
+            }
+        }
         self.stack.reset();
+        self.frames.clear();
+
+        InterpretError::RuntimeError {
+            message: message.to_string(),
+            line: innermost_line.unwrap_or(0),
+        }
     }
 }
 
-pub fn interpret(source: String) -> Result<(), InterpretError> {
-    let chunk = compiler::compile(source)?;
-    let mut vm = Vm::init(&chunk);
-    vm.interpret()?;
-    Ok(())
+// Negative indexes count back from the end of an array, so -1 is the last
+// element. Only arrays take them; bytes and strings don't.
+fn array_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        index.checked_add(i64::try_from(len).ok()?)?
+    } else {
+        index
+    };
+    usize::try_from(index).ok().filter(|&index| index < len)
+}
+
+pub fn is_falsey(value: &Value) -> bool {
+    matches!(value, Value::Nil | Value::Boolean(false))
+}
+
+// Compiles and runs a script on a fresh VM with the default options. This is
+// the entry point for embedding; the binary sets up its own VM to configure it.
+#[allow(dead_code)]
+pub fn interpret(source: impl Into<ArcStr>) -> Result<Value, InterpretError> {
+    let mut vm = Vm::init();
+    let compiled = compiler::compile(source, &CompileOptions::default(), vm.global_slots_mut())?;
+    compiled.warnings.iter().for_each(Diagnostic::print);
+    vm.load(Rc::new(compiled.function));
+    vm.interpret()
+}
+
+// Compiles and runs a script a few lines at a time, so only the declaration
+// being run is held in memory. Lines are gathered until they end a complete
+// top-level declaration, which then runs with the globals of those before it.
+// Compiler warnings are handed to `report_warnings` as each declaration compiles.
+pub fn interpret_streaming(
+    vm: &mut Vm,
+    reader: impl BufRead,
+    options: &CompileOptions,
+    mut report_warnings: impl FnMut(&[Diagnostic]),
+) -> Result<Value, InterpretError> {
+    let mut pending = String::new();
+    let mut first_line = 1;
+    let mut line_count = 0;
+    let mut lines = reader.lines();
+
+    loop {
+        let line = lines
+            .next()
+            .transpose()
+            .map_err(|e| InterpretError::CompileError {
+                diagnostics: vec![Diagnostic::error(
+                    first_line + line_count,
+                    format!("Failed to read source: {e}."),
+                )],
+                category: None,
+            })?;
+
+        // An `else` continues the `if` before it, so keep gathering.
+        let continues = line
+            .as_deref()
+            .is_some_and(|line| line.trim_start().starts_with("else"));
+        if !pending.is_empty() && (line.is_none() || (!continues && ends_declaration(&pending))) {
+            let options = CompileOptions {
+                line_offset: first_line - 1,
+                ..*options
+            };
+            let source = std::mem::take(&mut pending);
+            let compiled = compiler::compile(source, &options, vm.global_slots_mut())?;
+            if !compiled.warnings.is_empty() {
+                report_warnings(&compiled.warnings);
+            }
+            vm.load(Rc::new(compiled.function));
+            let result = vm.interpret()?;
+            if vm.script_returned {
+                return Ok(result);
+            }
+
+            first_line += line_count;
+            line_count = 0;
+        }
+
+        match line {
+            Some(line) => {
+                pending.push_str(&line);
+                pending.push('\n');
+                line_count += 1;
+            }
+            None => return Ok(Value::Nil),
+        }
+    }
+}
+
+// Whether the source ends a declaration with every bracket closed and every
+// `do` body followed by its `while`.
+fn ends_declaration(source: &str) -> bool {
+    let mut depth = 0i32;
+    let mut open_dos = 0;
+    let mut last = TokenType::Eof;
+    for token in Scanner::init(source) {
+        match token.token_type {
+            TokenType::Leftparen | TokenType::Leftbrace | TokenType::Leftbracket => depth += 1,
+            TokenType::Rightparen | TokenType::Rightbrace | TokenType::Rightbracket => depth -= 1,
+            TokenType::Do if depth <= 0 => open_dos += 1,
+            // A `while` right after a finished statement closes a `do`; one
+            // straight after `do` starts a loop that is the body.
+            TokenType::While
+                if depth <= 0
+                    && open_dos > 0
+                    && matches!(last, TokenType::Semicolon | TokenType::Rightbrace) =>
+            {
+                open_dos -= 1
+            }
+            TokenType::Error if token.lexeme == "Unterminated string." => return false,
+            TokenType::Eof => break,
+            _ => {}
+        }
+        last = token.token_type;
+    }
+    depth <= 0 && open_dos == 0 && matches!(last, TokenType::Semicolon | TokenType::Rightbrace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{output, run_function, runtime_error};
+
+    // A script made of the given bytes, all on line 1.
+    fn script(code: &[u8], constants: Vec<Value>) -> Function {
+        let mut function = Function::init("");
+        for byte in code {
+            function.chunk.write(*byte, 1);
+        }
+        for constant in constants {
+            function.chunk.add_constant(constant);
+        }
+        function
+    }
+
+    fn error_running(function: Function) -> String {
+        match run_function(function).0 {
+            Err(InterpretError::RuntimeError { message, .. }) => message,
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn calls_with_the_wrong_number_of_arguments_name_the_function() {
+        assert_eq!(
+            runtime_error("fun f(a, b) {} f(1);"),
+            "Expected 2 arguments but got 1 in call to f."
+        );
+        assert_eq!(
+            runtime_error("fun f(a, b) {} f(1, 2, 3);"),
+            "Expected 2 arguments but got 3 in call to f."
+        );
+    }
+
+    #[test]
+    fn truncated_chunks_are_reported_as_corrupt() {
+        let constant = u8::from(OpCode::Constant);
+        assert_eq!(
+            error_running(script(&[constant], vec![])),
+            "Corrupt bytecode: unexpected end of chunk."
+        );
+        assert_eq!(
+            error_running(script(&[constant, 3], vec![Value::Nil])),
+            "Corrupt bytecode: constant index 3 out of range."
+        );
+    }
+
+    #[test]
+    fn loops_back_past_the_start_are_reported_as_corrupt() {
+        assert_eq!(
+            error_running(script(&[OpCode::Loop.into(), 0, 9], vec![])),
+            "Corrupt bytecode: loop jumps before the start of the chunk."
+        );
+    }
+
+    #[test]
+    fn unary_plus_only_accepts_numbers() {
+        assert_eq!(output("print +5; print +int(3);"), "5\n3\n");
+        assert_eq!(runtime_error(r#"+"s";"#), "Operand must be a number");
+    }
+
+    #[test]
+    fn negating_the_smallest_integer_overflows() {
+        let min = "-int(9223372036854775807) - int(1)";
+        assert_eq!(output(&format!("print {min};")), "-9223372036854775808\n");
+        assert_eq!(
+            runtime_error(&format!("-({min});")),
+            "Integer overflow in negation."
+        );
+        assert_eq!(output("print -int(5); print -2.5;"), "-5\n-2.5\n");
+    }
+
+    #[test]
+    fn integers_work_as_indexes_and_counts() {
+        assert_eq!(
+            output(
+                r#"
+                var a = [1, 2, 3];
+                a[int(0)] = 9;
+                print a[int(1)];
+                print a[int(-1)];
+                print a;
+                print b"hi"[int(1)];
+                print substring("héllo", int(1), 3);
+                print int(2) * "ab";
+                "#
+            ),
+            "2\n3\n[9, 2, 3]\n105\nél\nabab\n"
+        );
+        assert_eq!(runtime_error("[1][1.5];"), "Index must be an integer.");
+    }
+
+    #[test]
+    fn swap_exchanges_the_top_two_values() {
+        let constant = u8::from(OpCode::Constant);
+        let print = u8::from(OpCode::Print);
+        let code = [constant, 0, constant, 1, OpCode::Swap.into(), print, print];
+        let values = vec![Value::Number(1.0), Value::Number(2.0)];
+        let (result, output) = run_function(script(&code, values));
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\n");
+
+        let code = [constant, 0, OpCode::Swap.into()];
+        assert_eq!(
+            error_running(script(&code, vec![Value::Nil])),
+            "Stack underflow."
+        );
+    }
+
+    #[test]
+    fn interpret_takes_a_str() {
+        assert!(matches!(interpret("print 1;"), Ok(Value::Nil)));
+        assert!(matches!(interpret("return 2;"), Ok(Value::Number(n)) if n == 2.0));
+        assert!(matches!(
+            interpret(String::from("print ;")),
+            Err(InterpretError::CompileError { .. })
+        ));
+    }
+
+    #[test]
+    fn out_of_range_local_slots_are_errors() {
+        for op in [OpCode::GetLocal, OpCode::SetLocal] {
+            let code = [OpCode::Nil.into(), op.into(), 5];
+            assert_eq!(error_running(script(&code, vec![])), "Invalid local slot.");
+        }
+    }
+
+    #[test]
+    fn booleans_only_add_and_multiply() {
+        assert_eq!(
+            output("print true + false; print true * false;"),
+            "true\nfalse\n"
+        );
+        for (op, a, b) in [
+            ("-", "true", "false"),
+            ("-", "false", "false"),
+            ("-", "true", "1"),
+            ("/", "true", "true"),
+            ("/", "false", "true"),
+            ("/", "1", "false"),
+        ] {
+            assert_eq!(
+                runtime_error(&format!("{a} {op} {b};")),
+                format!("Operands to '{op}' must be numbers."),
+                "{a} {op} {b}"
+            );
+        }
+    }
+
+    #[test]
+    fn max_steps_stops_runaway_loops() {
+        let (mut vm, _) = crate::testing::vm();
+        let options = CompileOptions::default();
+        let compiled = compiler::compile("while (true) {}", &options, vm.global_slots_mut());
+        vm.load(Rc::new(compiled.unwrap().function));
+        vm.set_max_steps(Some(100));
+        match vm.interpret() {
+            Err(InterpretError::RuntimeError { message, .. }) => {
+                assert_eq!(message, "Instruction limit exceeded.")
+            }
+            other => panic!("expected the limit to stop the loop, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn print_writes_utf8_bytes_to_the_output() {
+        let printed = output(r#"var s = "h" + "é"; print s + " ✓";"#);
+        assert_eq!(printed.as_bytes(), b"h\xc3\xa9 \xe2\x9c\x93\n");
+    }
+
+    #[test]
+    fn strings_cannot_be_subtracted() {
+        assert_eq!(
+            runtime_error(r#"print "banana" - "an";"#),
+            "Operands to '-' must be numbers."
+        );
+        assert_eq!(
+            runtime_error(r#"print "a" - 1;"#),
+            "Operands to '-' must be numbers."
+        );
+    }
+
+    #[test]
+    fn unbalanced_chunks_leave_values_on_the_stack() {
+        let constant = u8::from(OpCode::Constant);
+        let function = script(
+            &[constant, 0, constant, 1],
+            vec![Value::Number(1.0), Value::Number(2.0)],
+        );
+        let (mut vm, _) = crate::testing::vm();
+        vm.load(Rc::new(function));
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack().to_string(), "[<script>, 1, 2, ]");
+
+        let balanced = script(
+            &[
+                constant,
+                0,
+                OpCode::Print.into(),
+                OpCode::Nil.into(),
+                OpCode::Return.into(),
+            ],
+            vec![Value::Number(1.0)],
+        );
+        let (mut vm, _) = crate::testing::vm();
+        vm.load(Rc::new(balanced));
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack().to_string(), "[]");
+    }
+
+    #[test]
+    fn globals_defined_const_cannot_be_assigned() {
+        // The assignments compile before the globals are declared, so only the
+        // VM can tell the two apart.
+        assert_eq!(output("fun f() { b = 2; } var b = 1; f(); print b;"), "2\n");
+        assert_eq!(
+            runtime_error("fun f() { a = 2; } const a = 1; f();"),
+            "Cannot assign to const 'a'."
+        );
+    }
+
+    // A script of `lines` additions, written as it is read so it never exists whole.
+    struct Generated {
+        line: usize,
+        lines: usize,
+        buffer: Vec<u8>,
+    }
+
+    impl io::Read for Generated {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            if self.buffer.is_empty() {
+                self.buffer = match self.line {
+                    0 => "var total = 0;\n".to_string(),
+                    n if n <= self.lines => format!("total = total + {n};\n"),
+                    n if n == self.lines + 1 => "print total;\n".to_string(),
+                    _ => return Ok(0),
+                }
+                .into_bytes();
+                self.line += 1;
+            }
+            let len = out.len().min(self.buffer.len());
+            out[..len].copy_from_slice(&self.buffer[..len]);
+            self.buffer.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn stream(reader: impl BufRead) -> String {
+        let (mut vm, capture) = crate::testing::vm();
+        let options = CompileOptions::default();
+        interpret_streaming(&mut vm, reader, &options, |_| {}).unwrap();
+        capture.text()
+    }
+
+    #[test]
+    fn streaming_runs_a_generated_script() {
+        let script = Generated {
+            line: 0,
+            lines: 5000,
+            buffer: Vec::new(),
+        };
+        assert_eq!(stream(io::BufReader::new(script)), "12502500\n");
+    }
+
+    #[test]
+    fn streaming_keeps_do_while_loops_together() {
+        let source = "var i = 0;\ndo {\n  i = i + 1;\n}\nwhile (i < 3);\nprint i;\ndo\n  print \"once\";\nwhile (false);\n";
+        assert_eq!(stream(source.as_bytes()), "3\nonce\n");
+    }
+
+    #[test]
+    fn streaming_reports_warnings_as_declarations_compile() {
+        let (mut vm, _) = crate::testing::vm();
+        let mut warnings = Vec::new();
+        let source = "print 1;\nfun f() {\n  return 1;\n  print 2;\n}\n";
+        interpret_streaming(
+            &mut vm,
+            source.as_bytes(),
+            &CompileOptions::default(),
+            |found| warnings.extend(found.iter().map(|w| (w.line, w.message.clone()))),
+        )
+        .unwrap();
+        assert_eq!(warnings, [(4, "Unreachable code.".to_string())]);
+    }
+
+    #[test]
+    fn fields_round_trip_through_instances() {
+        assert_eq!(
+            output("class Foo {} var f = Foo(); f.x = 1; f.y = f.x + 1; print f.x; print f.y;"),
+            "1\n2\n"
+        );
+        assert_eq!(
+            runtime_error("class Foo {} print Foo().z;"),
+            "Undefined property 'z'."
+        );
+        assert_eq!(
+            runtime_error("var a = 1; print a.x;"),
+            "Only instances have properties."
+        );
+        assert_eq!(
+            runtime_error("var a = 1; a.x = 3;"),
+            "Only instances have fields."
+        );
+    }
+
+    #[test]
+    fn methods_see_their_instance_as_this() {
+        assert_eq!(
+            output(
+                r#"
+                class Person { greet(greeting) { return greeting + this.name; } }
+                var p = Person();
+                p.name = "Bo";
+                print p.greet("Hi ");
+                var bound = p.greet;
+                p.name = "Al";
+                print bound("Yo ");
+                "#
+            ),
+            "Hi Bo\nYo Al\n"
+        );
+        let errors = crate::testing::compile_errors("fun f() { print this; }");
+        assert_eq!(errors[0].message, "Can't use 'this' outside of a method.");
+    }
+
+    // Local names are only recorded in debug builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn local_slot_errors_name_the_variable() {
+        let mut function =
+            crate::testing::compile("{ var x = 1; print x; }", &CompileOptions::default());
+        let (offset, _, _) = function
+            .chunk
+            .instructions()
+            .find(|(_, op, _)| *op == OpCode::GetLocal)
+            .unwrap();
+        function.chunk.code[offset + 1] = 200;
+        assert_eq!(error_running(function), "Invalid local slot for 'x'.");
+    }
+
+    // The running instruction is only tracked in debug builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn panics_while_running_know_the_instruction() {
+        let (mut vm, _) = crate::testing::vm();
+        vm.define_native(NativeFunction {
+            name: "crash",
+            arity: 0,
+            function: |_, _| panic!("crash"),
+        });
+        let options = CompileOptions::default();
+        let compiled = compiler::compile("print 1;\ncrash();", &options, vm.global_slots_mut());
+        let function = compiled.unwrap().function;
+        let call = function
+            .chunk
+            .instructions()
+            .find(|(_, op, _)| *op == OpCode::Call);
+        let (offset, _, _) = call.unwrap();
+        vm.load(Rc::new(function));
+
+        let ran = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.interpret()));
+        assert!(ran.is_err());
+        assert_eq!(
+            panic_context(),
+            Some(format!("VM panicked at offset {offset} [line 2]."))
+        );
+    }
+
+    #[test]
+    fn calls_past_the_bottom_of_the_stack_are_errors() {
+        let function = script(&[OpCode::Nil.into(), OpCode::Call.into(), 9], vec![]);
+        assert_eq!(error_running(function), "Stack underflow");
+    }
+
+    #[test]
+    fn arithmetic_on_mismatched_types_names_the_operator() {
+        let add = "Operands to '+' must be two numbers, two strings, two booleans or two arrays.";
+        let multiply =
+            "Operands to '*' must be two numbers, two booleans or a string and a number.";
+        for (source, message) in [
+            (r#""a" - 1"#, "Operands to '-' must be numbers."),
+            ("[1] - [1]", "Operands to '-' must be numbers."),
+            (r#""a" / 2"#, "Operands to '/' must be numbers."),
+            ("nil / 1", "Operands to '/' must be numbers."),
+            ("nil + 1", add),
+            (r#"1 + "a""#, add),
+            ("[1] + 1", add),
+            (r#""a" * "b""#, multiply),
+            ("nil * 2", multiply),
+        ] {
+            assert_eq!(
+                runtime_error(&format!("print {source};")),
+                message,
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn negative_indexes_count_back_from_the_end() {
+        assert_eq!(
+            output("var a = [1, 2, 3]; print a[-1]; print a[-3]; a[-1] = 9; print a;"),
+            "3\n1\n[1, 2, 9]\n"
+        );
+        assert_eq!(
+            runtime_error("print [1, 2, 3][-4];"),
+            "Index out of bounds."
+        );
+        assert_eq!(
+            runtime_error("var a = [1]; a[-2] = 0;"),
+            "Index out of bounds."
+        );
+        assert_eq!(
+            runtime_error(r#"print b"abc"[-1];"#),
+            "Index must be a non-negative integer."
+        );
+    }
+
+    #[test]
+    fn the_trace_log_has_a_line_per_instruction() {
+        let (mut vm, _) = crate::testing::vm();
+        let trace = crate::testing::Capture::default();
+        vm.set_trace_log(Some(Box::new(trace.clone())));
+        let function = crate::testing::compile("print 2 + 3;", &CompileOptions::default());
+        let ops: Vec<String> = function
+            .chunk
+            .instructions()
+            .map(|(_, op, _)| format!("{op:?}"))
+            .collect();
+        vm.load(Rc::new(function));
+        vm.interpret().unwrap();
+
+        let text = trace.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), ops.len(), "{text}");
+        for (line, op) in lines.iter().zip(&ops) {
+            assert!(line.contains(op.as_str()), "{line} runs {op}");
+        }
+        assert!(lines[2].starts_with("0004\t"), "{text}");
+        assert!(lines[2].ends_with("[<script>, 2, 3, ]"), "{text}");
+    }
 }
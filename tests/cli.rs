@@ -0,0 +1,161 @@
+// Runs the thorium binary on small scripts to check what only the whole
+// process shows: exit codes and what reaches stdout and stderr.
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+// A file in the temp directory, named after the test so tests can run in parallel.
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("thorium-{}-{name}", std::process::id()));
+    fs::write(&path, contents).expect("Failed to write temp file");
+    path
+}
+
+fn thorium(name: &str, args: &[&str], source: &str) -> Output {
+    let path = temp_file(&format!("{name}.th"), source);
+    let output = Command::new(env!("CARGO_BIN_EXE_thorium"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .expect("Failed to run thorium");
+    let _ = fs::remove_file(path);
+    output
+}
+
+// Runs the REPL with `lines` as its input.
+fn repl(args: &[&str], lines: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_thorium"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run thorium");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(lines.as_bytes())
+        .expect("Failed to write REPL input");
+    child.wait_with_output().expect("Failed to run thorium")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn error_native_exits_70() {
+    let output = thorium("error_native", &[], r#"error("boom");"#);
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output).starts_with("boom\n"));
+}
+
+#[test]
+fn failed_assert_eq_shows_both_values_and_exits_70() {
+    let output = thorium("assert_eq", &[], "assert_eq(1, 1); assert_eq(1 + 1, 3);");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output).starts_with("Assertion failed: 2 != 3\n"));
+}
+
+#[test]
+fn compile_errors_exit_by_category() {
+    for (name, source, code) in [
+        ("scan_error", "print @;", 66),
+        ("unterminated", "print \"abc;", 66),
+        ("parse_error", "print 1 +;", 65),
+        ("resolve_error", "print this;", 67),
+    ] {
+        let output = thorium(name, &[], source);
+        assert_eq!(output.status.code(), Some(code), "{source}");
+    }
+}
+
+#[test]
+fn transcript_records_lines_and_everything_shown() {
+    let path = temp_file("transcript.md", "");
+    let flag = format!("--transcript={}", path.display());
+    let input = "var x = 2;\nprint x * 3;\n:globals\nprint y;\nprint (;\n\nprint 4;\n";
+    let output = repl(&[&flag], input);
+    assert_eq!(output.status.code(), Some(0));
+
+    let transcript = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(path);
+    assert_eq!(
+        transcript,
+        "> var x = 2;\n\
+         > print x * 3;\n\
+         6\n\
+         > :globals\n\
+         x = 2\n\
+         > print y;\n\
+         Variable y is not known.\n\
+         [line 1] in script\n\
+         > print (;\n\
+         [line 1] Error at ';': Expect expression.\n    print (;\n           ^\n"
+    );
+}
+
+#[test]
+fn check_compiles_without_running() {
+    // Running it would exit with 3.
+    let good = thorium("check_good", &["--check"], "exit(3);");
+    assert_eq!(good.status.code(), Some(0));
+
+    let bad = thorium("check_bad", &["--check"], "print (;");
+    assert_eq!(bad.status.code(), Some(65));
+    assert!(stderr(&bad).contains("Expect expression."));
+}
+
+#[test]
+fn exit_sets_the_status() {
+    let output = thorium("exit", &[], "exit(3); print 1;");
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = thorium("exit_range", &[], "exit(256);");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output).starts_with("Exit code must be between 0 and 255.\n"));
+}
+
+#[test]
+fn json_diagnostics_are_valid_json() {
+    let output = thorium("json", &["--emit-json-diagnostics"], "print (;\nvar 1;\n");
+    assert_eq!(output.status.code(), Some(65));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json = stdout.lines().last().unwrap();
+    let diagnostics: serde_json::Value = serde_json::from_str(json).unwrap();
+    assert_eq!(
+        diagnostics,
+        serde_json::json!([
+            {"line": 1, "column": 8, "message": "Expect expression.", "severity": "error"},
+            {"line": 2, "column": 5, "message": "Expect a variable name.", "severity": "error"},
+        ])
+    );
+
+    let output = thorium("json_runtime", &["--emit-json-diagnostics"], "print nope;");
+    assert_eq!(output.status.code(), Some(70));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json = stdout.lines().last().unwrap();
+    let diagnostics: serde_json::Value = serde_json::from_str(json).unwrap();
+    assert_eq!(diagnostics[0]["message"], "Variable nope is not known.");
+}
+
+#[test]
+fn repl_history_keeps_lines_across_sessions() {
+    let path = temp_file("history", "");
+    let _ = fs::remove_file(&path);
+    let flag = format!("--repl-history={}", path.display());
+    repl(&[&flag], "var x = 1;\nprint x;\n\n");
+    repl(&[&flag], "print 2;\n");
+
+    let history = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(path);
+    let lines: Vec<_> = history
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+    assert_eq!(lines, ["var x = 1;", "print x;", "print 2;"]);
+}